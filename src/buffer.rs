@@ -0,0 +1,275 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use log::{error, warn};
+
+/// Persistent FIFO queue for batches of InfluxDB lines.
+///
+/// Each batch is stored in its own file named by an increasing sequence number.
+/// The queue content survives process restarts.
+pub struct DiskBuffer {
+    dir: PathBuf,
+    max_size: u64,
+    size: u64,
+    next_seq: u64,
+    /// Sequence numbers and sizes of the stored batches, oldest first
+    batches: VecDeque<(u64, u64)>,
+}
+
+impl DiskBuffer {
+    const EXTENSION: &'static str = "lp";
+    const TMP_EXTENSION: &'static str = "tmp";
+
+    /// Opens the buffer directory, creates it if necessary.
+    /// `max_size` is the upper limit for the sum of all stored batches in bytes.
+    pub fn open(dir: impl Into<PathBuf>, max_size: u64) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+
+        let mut batches = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let ext = path.extension().and_then(|e| e.to_str());
+            let seq = path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| s.parse::<u64>().ok());
+
+            match (ext, seq) {
+                (Some(Self::EXTENSION), Some(seq)) => {
+                    batches.push((seq, fs::metadata(&path)?.len()));
+                }
+                // Left over from an interrupted write
+                (Some(Self::TMP_EXTENSION), Some(_)) => fs::remove_file(&path)?,
+                _ => warn!("Buffer: Ignoring unknown file {}", path.display()),
+            }
+        }
+        batches.sort_unstable();
+
+        let mut buffer = Self {
+            dir,
+            max_size,
+            size: batches.iter().map(|(_, len)| len).sum(),
+            next_seq: batches.last().map_or(0, |(seq, _)| seq + 1),
+            batches: batches.into(),
+        };
+        buffer.shrink(0)?;
+        Ok(buffer)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.batches.is_empty()
+    }
+
+    /// Number of stored batches
+    pub fn len(&self) -> usize {
+        self.batches.len()
+    }
+
+    /// Appends a batch. Drops the oldest batches when the size limit is exceeded.
+    pub fn push(&mut self, batch: &str) -> io::Result<()> {
+        let len = batch.len() as u64;
+        self.shrink(len)?;
+
+        // Write to a temporary file first so that a crash never leaves a truncated batch
+        let seq = self.next_seq;
+        let tmp_path = self.path(seq, Self::TMP_EXTENSION);
+        fs::write(&tmp_path, batch)?;
+        fs::rename(&tmp_path, self.path(seq, Self::EXTENSION))?;
+
+        self.next_seq += 1;
+        self.size += len;
+        self.batches.push_back((seq, len));
+        Ok(())
+    }
+
+    /// Returns the oldest batches combined into one, as long as they have at most
+    /// `max_lines` lines in total (the oldest batch is always included), and the
+    /// sequence number of the newest included batch. Nothing is removed.
+    ///
+    /// Batches that cannot be read are deleted, they would block the buffer forever.
+    pub fn front(&mut self, max_lines: usize) -> io::Result<Option<(u64, String)>> {
+        let mut lines = String::new();
+        let mut num_lines = 0;
+        let mut last_seq = None;
+        let mut index = 0;
+        while let Some(&(seq, _)) = self.batches.get(index) {
+            let path = self.path(seq, Self::EXTENSION);
+            let batch = match fs::read_to_string(&path) {
+                Ok(batch) => batch,
+                Err(e) => {
+                    error!("Buffer: Deleting unreadable {}: {}", path.display(), e);
+                    self.remove(index)?;
+                    continue;
+                }
+            };
+            let len = batch.lines().count();
+            if last_seq.is_some() && num_lines + len > max_lines {
+                break;
+            }
+            lines.push_str(&batch);
+            num_lines += len;
+            last_seq = Some(seq);
+            index += 1;
+        }
+        Ok(last_seq.map(|seq| (seq, lines)))
+    }
+
+    /// Removes the batches up to `seq` returned by `front()`.
+    /// Batches dropped in the meantime to respect the size limit are skipped.
+    pub fn pop(&mut self, seq: u64) -> io::Result<()> {
+        while matches!(self.batches.front(), Some((front_seq, _)) if *front_seq <= seq) {
+            self.remove(0)?;
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, index: usize) -> io::Result<()> {
+        if let Some((seq, len)) = self.batches.remove(index) {
+            self.size -= len;
+            fs::remove_file(self.path(seq, Self::EXTENSION))?;
+        }
+        Ok(())
+    }
+
+    /// Drops the oldest batches until `additional` bytes fit into the buffer.
+    fn shrink(&mut self, additional: u64) -> io::Result<()> {
+        let mut dropped = 0;
+        while !self.is_empty() && self.size + additional > self.max_size {
+            self.remove(0)?;
+            dropped += 1;
+        }
+        if dropped > 0 {
+            warn!(
+                "Buffer: Size limit reached, dropped {} oldest batches",
+                dropped
+            );
+        }
+        Ok(())
+    }
+
+    fn path(&self, seq: u64, extension: &str) -> PathBuf {
+        self.dir.join(format!("{:020}.{}", seq, extension))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::process;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("data-collector-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_disk_buffer() {
+        let dir = test_dir("buffer");
+
+        let mut buffer = DiskBuffer::open(&dir, 1000).unwrap();
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.front(1).unwrap(), None);
+
+        buffer.push("a value=1 1\n").unwrap();
+        buffer.push("b value=2 2\n").unwrap();
+        buffer.push("c value=3 3\n").unwrap();
+        let (seq, batch) = buffer.front(1).unwrap().unwrap();
+        assert_eq!(batch, "a value=1 1\n");
+        buffer.pop(seq).unwrap();
+        drop(buffer);
+
+        // Content and order survive a restart
+        let mut buffer = DiskBuffer::open(&dir, 1000).unwrap();
+        assert_eq!(buffer.len(), 2);
+        buffer.push("d value=4 4\n").unwrap();
+        for expected in &["b", "c", "d"] {
+            let (seq, batch) = buffer.front(1).unwrap().unwrap();
+            assert!(batch.starts_with(expected));
+            buffer.pop(seq).unwrap();
+        }
+        assert!(buffer.is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_disk_buffer_size_limit() {
+        let dir = test_dir("buffer-limit");
+
+        // Room for two batches of 12 bytes
+        let mut buffer = DiskBuffer::open(&dir, 30).unwrap();
+        buffer.push("a value=1 1\n").unwrap();
+        let (seq, _) = buffer.front(1).unwrap().unwrap();
+        buffer.push("b value=2 2\n").unwrap();
+        buffer.push("c value=3 3\n").unwrap();
+        assert_eq!(buffer.len(), 2);
+
+        // The batch from `front()` was dropped, `b` must not be removed
+        buffer.pop(seq).unwrap();
+        assert_eq!(buffer.len(), 2);
+        assert_eq!(buffer.front(1).unwrap().unwrap().1, "b value=2 2\n");
+        drop(buffer);
+
+        // Opening with a smaller limit drops the oldest batches
+        let mut buffer = DiskBuffer::open(&dir, 20).unwrap();
+        assert_eq!(buffer.len(), 1);
+        assert_eq!(buffer.front(1).unwrap().unwrap().1, "c value=3 3\n");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_disk_buffer_merge() {
+        let dir = test_dir("buffer-merge");
+
+        let mut buffer = DiskBuffer::open(&dir, 1000).unwrap();
+        buffer.push("a value=1 1\nb value=2 2\n").unwrap();
+        buffer.push("c value=3 3\n").unwrap();
+        buffer.push("d value=4 4\ne value=5 5\n").unwrap();
+
+        // Batches are never split, the oldest one is returned even if it is too long
+        assert_eq!(
+            buffer.front(1).unwrap().unwrap().1,
+            "a value=1 1\nb value=2 2\n"
+        );
+        let (seq, batch) = buffer.front(4).unwrap().unwrap();
+        assert_eq!(batch, "a value=1 1\nb value=2 2\nc value=3 3\n");
+        buffer.pop(seq).unwrap();
+        assert_eq!(buffer.len(), 1);
+        assert_eq!(
+            buffer.front(4).unwrap().unwrap().1,
+            "d value=4 4\ne value=5 5\n"
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_disk_buffer_unreadable_batch() {
+        let dir = test_dir("buffer-unreadable");
+
+        let mut buffer = DiskBuffer::open(&dir, 1000).unwrap();
+        buffer.push("a value=1 1\n").unwrap();
+        buffer.push("b value=2 2\n").unwrap();
+        drop(buffer);
+        // Not valid UTF-8
+        let path = dir.join(format!("{:020}.lp", 0));
+        fs::write(&path, [0xff, 0xfe]).unwrap();
+
+        let mut buffer = DiskBuffer::open(&dir, 1000).unwrap();
+        assert_eq!(
+            buffer.front(10).unwrap().unwrap(),
+            (1, String::from("b value=2 2\n"))
+        );
+        assert_eq!(buffer.len(), 1);
+        assert!(!path.exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,55 +1,423 @@
-use std::collections::BTreeMap;
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error as StdError;
+use std::fmt;
+use std::io;
+use std::time::Duration;
 
-use crate::device::{DataType, Device, Register};
-use humantime;
+use crate::device::{Bits, ByteOrder, DataType, Device, Register, RegisterType, RequestOptions};
+use crate::queue::Overflow;
+use crate::rtu::{Config as ModbusRtuConfig, Transport as ModbusRtuTransport};
 use isahc::http::Request;
-use modbus::tcp::Config as ModbusTcpConfig;
+use log::debug;
+use modbus::tcp::{Config as ModbusTcpConfig, Transport as ModbusTcpTransport};
+use modbus::{Client, Error as ModbusError};
+use rumqttc::QoS;
 use serde::Deserialize;
+use serialport::{DataBits, Parity, StopBits};
+
+/// Name of the connection configured in the `[modbus]` section
+pub const DEFAULT_CONNECTION: &str = "modbus";
+
+/// Error of a field value which cannot be parsed
+fn invalid_field(field: &str, value: impl fmt::Display, reason: impl fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("Invalid `{}` `{}`: {}", field, value, reason),
+    )
+}
+
+/// All problems found in the configuration, one per line
+#[derive(Debug, Default)]
+pub struct Errors(Vec<String>);
+
+impl Errors {
+    fn add(&mut self, context: &str, message: impl fmt::Display) {
+        self.0.push(format!("{}: {}", context, message));
+    }
+
+    /// Adds an error if the optional field is set but cannot be parsed
+    fn check_field<T, E: fmt::Display>(
+        &mut self,
+        context: &str,
+        field: &str,
+        value: Option<&str>,
+        parse: impl FnOnce(&str) -> Result<T, E>,
+    ) {
+        if let Some(value) = value {
+            if let Err(e) = parse(value) {
+                self.add(
+                    context,
+                    format_args!("Invalid `{}` `{}`: {}", field, value, e),
+                );
+            }
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn into_result<T>(self, value: T) -> Result<T, Errors> {
+        if self.0.is_empty() {
+            Ok(value)
+        } else {
+            Err(self)
+        }
+    }
+}
+
+impl fmt::Display for Errors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0.join("\n"))
+    }
+}
+
+impl StdError for Errors {}
 
 #[derive(Deserialize)]
 pub struct Config {
-    pub modbus: ModbusConfig,
+    pub modbus: Option<ModbusConfig>,
 
-    #[serde(flatten)]
-    pub influxdb: InfluxDbConfig,
+    #[serde(default)]
+    pub connections: BTreeMap<String, ModbusConfig>,
+
+    pub influxdb: Option<InfluxDbConfig>,
+    pub influxdb2: Option<InfluxDbConfig>,
+    pub mqtt: Option<MqttConfig>,
+    pub prometheus: Option<PrometheusConfig>,
+
+    #[serde(default)]
+    pub outputs: BTreeMap<String, OutputConfig>,
 
     #[serde(flatten)]
     pub devices: DevicesConfig,
 }
 
+/// Checked configuration with all connections and outputs by name
+pub struct Setup {
+    pub connections: BTreeMap<String, ModbusConfig>,
+    pub outputs: BTreeMap<String, OutputConfig>,
+    pub devices: Vec<Device>,
+}
+
+impl Setup {
+    /// Checks what is only needed to collect data,
+    /// the `read`, `scan`, `probe` and `simulate` commands work without devices or outputs.
+    pub fn check_collecting(&self) -> Result<(), Errors> {
+        let mut errors = Errors::default();
+        if self.devices.is_empty() {
+            errors.add("Devices", "None configured, add a `[[devices]]` section");
+        }
+        if self.outputs.is_empty() {
+            errors.add(
+                "Outputs",
+                "None configured, add an `[outputs.<output_name>]` section",
+            );
+        }
+        errors.into_result(())
+    }
+}
+
+impl Config {
+    /// Merges the `[modbus]` section into the connections and the `[influxdb]`, `[influxdb2]`,
+    /// `[mqtt]` and `[prometheus]` sections into the outputs named after the section.
+    /// Checks the whole configuration and reports all problems at once.
+    pub fn into_setup(self) -> Result<Setup, Errors> {
+        let mut errors = Errors::default();
+
+        let mut connections = self.connections;
+        if let Some(modbus_config) = self.modbus {
+            if connections
+                .insert(String::from(DEFAULT_CONNECTION), modbus_config)
+                .is_some()
+            {
+                errors.add(
+                    &format!("Connection `{}`", DEFAULT_CONNECTION),
+                    "Defined twice",
+                );
+            }
+        }
+        for (name, connection) in &connections {
+            connection.check(&format!("Connection `{}`", name), &mut errors);
+        }
+
+        let mut outputs = self.outputs;
+        let sections = [
+            ("influxdb", self.influxdb.map(OutputConfig::InfluxDb)),
+            ("influxdb2", self.influxdb2.map(OutputConfig::InfluxDb)),
+            ("mqtt", self.mqtt.map(OutputConfig::Mqtt)),
+            ("prometheus", self.prometheus.map(OutputConfig::Prometheus)),
+        ];
+        for (name, output) in sections {
+            if let Some(output) = output {
+                if outputs.insert(String::from(name), output).is_some() {
+                    errors.add(&format!("Output `{}`", name), "Defined twice");
+                }
+            }
+        }
+        let mut buffer_paths = BTreeSet::new();
+        for (name, output) in &outputs {
+            let context = format!("Output `{}`", name);
+            output.check(&context, &mut errors);
+            if let OutputConfig::InfluxDb(InfluxDbConfig {
+                buffer: Some(buffer),
+                ..
+            }) = output
+            {
+                if !buffer_paths.insert(&buffer.path) {
+                    errors.add(
+                        &context,
+                        format_args!("Buffer `{}` used by another output", buffer.path),
+                    );
+                }
+            }
+        }
+
+        // Connections and outputs are only checked for valid devices
+        let devices = self
+            .devices
+            .into_devices_with_context()
+            .unwrap_or_else(|e| {
+                errors.0.extend(e.0);
+                Vec::new()
+            });
+        for (context, dev) in &devices {
+            if !connections.contains_key(&dev.connection) {
+                errors.add(
+                    context,
+                    format_args!("Unknown connection `{}`", dev.connection),
+                );
+            }
+            let unknown: BTreeSet<_> = dev
+                .outputs()
+                .filter(|output| !outputs.contains_key(*output))
+                .collect();
+            for output in unknown {
+                errors.add(context, format_args!("Unknown output `{}`", output));
+            }
+        }
+
+        errors.into_result(Setup {
+            connections,
+            outputs,
+            devices: devices.into_iter().map(|(_, dev)| dev).collect(),
+        })
+    }
+}
+
 #[derive(Deserialize)]
 pub struct ModbusConfig {
-    pub hostname: String,
-    pub port: u16,
     pub timeout: String,
+    pub reconnect_max_delay: Option<String>,
+
+    #[serde(flatten)]
+    transport: TransportConfig,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TransportConfig {
+    Tcp {
+        hostname: String,
+        port: u16,
+    },
+    Rtu {
+        device: String,
+        baud_rate: u32,
+
+        // Workaround for untagged enums: Option and unwrap_or()
+        parity: Option<String>,
+        data_bits: Option<u8>,
+        stop_bits: Option<u8>,
+        frame_delay: Option<String>,
+    },
 }
 
 impl ModbusConfig {
-    pub fn into_modbus_tcp_config(self) -> (String, ModbusTcpConfig) {
-        let timeout = humantime::parse_duration(&self.timeout).unwrap();
-        (
-            self.hostname,
-            ModbusTcpConfig {
-                tcp_port: self.port,
-                tcp_connect_timeout: None,
-                tcp_read_timeout: Some(timeout),
-                tcp_write_timeout: Some(timeout),
-                modbus_uid: 0,
-            },
-        )
+    /// Adds all invalid fields to `errors`.
+    pub(crate) fn check(&self, context: &str, errors: &mut Errors) {
+        if let Err(e) = self.timeout() {
+            errors.add(context, e);
+        }
+        if let Err(e) = self.reconnect_max_delay() {
+            errors.add(context, e);
+        }
+
+        if let TransportConfig::Rtu {
+            baud_rate,
+            parity,
+            data_bits,
+            stop_bits,
+            frame_delay,
+            ..
+        } = &self.transport
+        {
+            if *baud_rate == 0 {
+                errors.add(context, "`baud_rate` must not be zero");
+            }
+            errors.check_field(context, "parity", parity.as_deref(), parse_parity);
+            if let Some(b) = data_bits.filter(|b| parse_data_bits(*b).is_none()) {
+                errors.add(context, format_args!("Invalid `data_bits` `{}`", b));
+            }
+            if let Some(b) = stop_bits.filter(|b| parse_stop_bits(*b).is_none()) {
+                errors.add(context, format_args!("Invalid `stop_bits` `{}`", b));
+            }
+            errors.check_field(
+                context,
+                "frame_delay",
+                frame_delay.as_deref(),
+                humantime::parse_duration,
+            );
+        }
+    }
+
+    pub fn connect(&self) -> Result<Box<dyn Client>, ModbusError> {
+        self.connect_with_timeout(self.timeout()?)
+    }
+
+    fn timeout(&self) -> io::Result<Duration> {
+        humantime::parse_duration(&self.timeout)
+            .map_err(|e| invalid_field("timeout", &self.timeout, e))
+    }
+
+    /// Like `connect()` but overrides the configured timeout.
+    pub fn connect_with_timeout(&self, timeout: Duration) -> Result<Box<dyn Client>, ModbusError> {
+        match &self.transport {
+            TransportConfig::Tcp { hostname, port } => {
+                let config = ModbusTcpConfig {
+                    tcp_port: *port,
+                    tcp_connect_timeout: Some(timeout),
+                    tcp_read_timeout: Some(timeout),
+                    tcp_write_timeout: Some(timeout),
+                    modbus_uid: 0,
+                };
+
+                debug!("Connecting to {}:{}", hostname, port);
+                Ok(Box::new(ModbusTcpTransport::new_with_cfg(
+                    hostname, config,
+                )?))
+            }
+            TransportConfig::Rtu {
+                device,
+                baud_rate,
+                parity,
+                data_bits,
+                stop_bits,
+                frame_delay,
+            } => {
+                let parity = parity.as_deref().unwrap_or("even");
+                let data_bits = data_bits.unwrap_or(8);
+                let stop_bits = stop_bits.unwrap_or(1);
+                let config = ModbusRtuConfig {
+                    baud_rate: *baud_rate,
+                    parity: parse_parity(parity).map_err(|e| invalid_field("parity", parity, e))?,
+                    data_bits: parse_data_bits(data_bits)
+                        .ok_or_else(|| invalid_field("data_bits", data_bits, "Use 5, 6, 7 or 8"))?,
+                    stop_bits: parse_stop_bits(stop_bits)
+                        .ok_or_else(|| invalid_field("stop_bits", stop_bits, "Use 1 or 2"))?,
+                    timeout,
+                    frame_delay: frame_delay
+                        .as_ref()
+                        .map(|d| {
+                            humantime::parse_duration(d)
+                                .map_err(|e| invalid_field("frame_delay", d, e))
+                        })
+                        .transpose()?,
+                };
+
+                debug!("Opening {}", device);
+                Ok(Box::new(ModbusRtuTransport::new_with_cfg(device, config)?))
+            }
+        }
+    }
+
+    /// `None` for serial connections
+    pub fn tcp_port(&self) -> Option<u16> {
+        match &self.transport {
+            TransportConfig::Tcp { port, .. } => Some(*port),
+            TransportConfig::Rtu { .. } => None,
+        }
+    }
+
+    pub fn reconnect_max_delay(&self) -> io::Result<Duration> {
+        match &self.reconnect_max_delay {
+            Some(d) => {
+                humantime::parse_duration(d).map_err(|e| invalid_field("reconnect_max_delay", d, e))
+            }
+            None => Ok(Duration::from_secs(60)),
+        }
     }
 }
 
-#[derive(Deserialize)]
-pub enum InfluxDbConfig {
-    #[serde(rename = "influxdb")]
+fn parse_parity(parity: &str) -> Result<Parity, &'static str> {
+    match parity {
+        "none" => Ok(Parity::None),
+        "even" => Ok(Parity::Even),
+        "odd" => Ok(Parity::Odd),
+        _ => Err("Use \"none\", \"even\" or \"odd\""),
+    }
+}
+
+fn parse_data_bits(data_bits: u8) -> Option<DataBits> {
+    match data_bits {
+        5 => Some(DataBits::Five),
+        6 => Some(DataBits::Six),
+        7 => Some(DataBits::Seven),
+        8 => Some(DataBits::Eight),
+        _ => None,
+    }
+}
+
+fn parse_stop_bits(stop_bits: u8) -> Option<StopBits> {
+    match stop_bits {
+        1 => Some(StopBits::One),
+        2 => Some(StopBits::Two),
+        _ => None,
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum OutputConfig {
+    #[serde(rename = "influxdb", alias = "influxdb2")]
+    InfluxDb(InfluxDbConfig),
+    Mqtt(MqttConfig),
+    Prometheus(PrometheusConfig),
+}
+
+impl OutputConfig {
+    fn check(&self, context: &str, errors: &mut Errors) {
+        match self {
+            OutputConfig::InfluxDb(c) => c.check(context, errors),
+            OutputConfig::Mqtt(c) => c.check(context, errors),
+            OutputConfig::Prometheus(_) => {}
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+pub struct InfluxDbConfig {
+    #[serde(flatten)]
+    api: InfluxDbApi,
+
+    queue_size: Option<usize>,
+    overflow: Option<String>,
+    batch_size: Option<usize>,
+    flush_interval: Option<String>,
+    gzip: Option<bool>,
+    pub buffer: Option<BufferConfig>,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(untagged)]
+enum InfluxDbApi {
     V1 {
         hostname: String,
         database: String,
         username: Option<String>,
         password: Option<String>,
     },
-    #[serde(rename = "influxdb2")]
     V2 {
         hostname: String,
         organization: String,
@@ -59,11 +427,59 @@ pub enum InfluxDbConfig {
 }
 
 impl InfluxDbConfig {
-    pub fn to_request<T>(&self, lines: T) -> Request<T> {
+    fn check(&self, context: &str, errors: &mut Errors) {
+        if let Err(e) = self.overflow() {
+            errors.add(context, e);
+        }
+        if let Err(e) = self.flush_interval() {
+            errors.add(context, e);
+        }
+        if self.queue_size() == 0 {
+            errors.add(context, "`queue_size` must not be zero");
+        }
+        if self.batch_size() == 0 {
+            errors.add(context, "`batch_size` must not be zero");
+        }
+        if self.overflow.as_deref() == Some("spill") && self.buffer.is_none() {
+            errors.add(context, "Overflow policy `spill` requires a buffer");
+        }
+    }
+
+    pub fn queue_size(&self) -> usize {
+        self.queue_size.unwrap_or(1000)
+    }
+
+    pub fn overflow(&self) -> io::Result<Overflow> {
+        match &self.overflow {
+            Some(o) => o.parse().map_err(|_| {
+                invalid_field("overflow", o, "Use \"drop_oldest\", \"block\" or \"spill\"")
+            }),
+            None => Ok(Overflow::DropOldest),
+        }
+    }
+
+    pub fn batch_size(&self) -> usize {
+        self.batch_size.unwrap_or(5000)
+    }
+
+    pub fn flush_interval(&self) -> io::Result<Duration> {
+        match &self.flush_interval {
+            Some(d) => {
+                humantime::parse_duration(d).map_err(|e| invalid_field("flush_interval", d, e))
+            }
+            None => Ok(Duration::default()),
+        }
+    }
+
+    pub fn gzip(&self) -> bool {
+        self.gzip.unwrap_or(false)
+    }
+
+    pub(crate) fn to_request<T>(&self, lines: T) -> Request<T> {
         let mut req = Request::builder();
 
-        match self {
-            InfluxDbConfig::V1 {
+        match &self.api {
+            InfluxDbApi::V1 {
                 hostname,
                 database,
                 username,
@@ -75,7 +491,7 @@ impl InfluxDbConfig {
                 }
                 req.uri(uri);
             }
-            InfluxDbConfig::V2 {
+            InfluxDbApi::V2 {
                 hostname,
                 organization,
                 bucket,
@@ -95,129 +511,859 @@ impl InfluxDbConfig {
     }
 }
 
+#[derive(Clone, Deserialize)]
+pub struct BufferConfig {
+    pub path: String,
+    max_size: Option<u64>,
+}
+
+impl BufferConfig {
+    pub fn max_size(&self) -> u64 {
+        self.max_size.unwrap_or(100_000_000)
+    }
+}
+
+#[derive(Clone, Deserialize)]
+pub struct MqttConfig {
+    pub hostname: String,
+    port: Option<u16>,
+    client_id: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub topic: String,
+    format: Option<String>,
+    qos: Option<u8>,
+    retain: Option<bool>,
+    pub status_topic: Option<String>,
+    tls: Option<bool>,
+    pub ca_file: Option<String>,
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+    queue_size: Option<usize>,
+}
+
+/// Payload of the MQTT messages
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MqttFormat {
+    /// One message for each value containing only the value
+    Value,
+    /// One JSON message with all values for each device reading
+    Json,
+}
+
+impl MqttConfig {
+    fn check(&self, context: &str, errors: &mut Errors) {
+        if let Err(e) = self.format() {
+            errors.add(context, e);
+        }
+        if let Err(e) = self.qos() {
+            errors.add(context, e);
+        }
+        if self.queue_size() == 0 {
+            errors.add(context, "`queue_size` must not be zero");
+        }
+        if self.client_cert.is_some() != self.client_key.is_some() {
+            errors.add(
+                context,
+                "`client_cert` and `client_key` must be set together",
+            );
+        }
+        if self.client_cert.is_some() && self.ca_file.is_none() {
+            errors.add(context, "`client_cert` requires `ca_file`");
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+            .unwrap_or_else(|| if self.tls() { 8883 } else { 1883 })
+    }
+
+    pub fn client_id(&self) -> &str {
+        self.client_id.as_deref().unwrap_or("data-collector")
+    }
+
+    pub fn format(&self) -> io::Result<MqttFormat> {
+        match self.format.as_deref() {
+            None | Some("value") => Ok(MqttFormat::Value),
+            Some("json") => Ok(MqttFormat::Json),
+            Some(f) => Err(invalid_field("format", f, "Use \"value\" or \"json\"")),
+        }
+    }
+
+    pub fn qos(&self) -> io::Result<QoS> {
+        let qos = self.qos.unwrap_or(0);
+        rumqttc::qos(qos).map_err(|_| invalid_field("qos", qos, "Use 0, 1 or 2"))
+    }
+
+    pub fn retain(&self) -> bool {
+        self.retain.unwrap_or(false)
+    }
+
+    pub fn tls(&self) -> bool {
+        self.tls.unwrap_or(false)
+    }
+
+    pub fn queue_size(&self) -> usize {
+        self.queue_size.unwrap_or(1000)
+    }
+}
+
+#[derive(Clone, Deserialize)]
+pub struct PrometheusConfig {
+    listen: Option<String>,
+    pub prefix: Option<String>,
+}
+
+impl PrometheusConfig {
+    pub fn listen(&self) -> &str {
+        self.listen.as_deref().unwrap_or("0.0.0.0:9502")
+    }
+}
+
 #[derive(Deserialize)]
 pub struct DevicesConfig {
     #[serde(default)]
     templates: BTreeMap<String, DeviceConfig>,
+    #[serde(default)]
     devices: Vec<DeviceConfig>,
 }
 
 impl DevicesConfig {
-    pub fn into_devices(self) -> Vec<Device> {
+    /// Merges the templates into the devices. Reports the problems of all devices at once.
+    pub fn into_devices(self) -> Result<Vec<Device>, Errors> {
+        let devices = self.into_devices_with_context()?;
+        Ok(devices.into_iter().map(|(_, dev)| dev).collect())
+    }
+
+    /// Each device with the context used in its error messages
+    fn into_devices_with_context(self) -> Result<Vec<(String, Device)>, Errors> {
+        let mut errors = Errors::default();
         let mut devices = Vec::new();
-        for config in self.devices {
-            devices.push(device_from_config(&self.templates, config));
+        for (index, config) in self.devices.into_iter().enumerate() {
+            if let Some(dev) = device_from_config(&self.templates, config, index, &mut errors) {
+                devices.push(dev);
+            }
         }
-        devices
+        errors.into_result(devices)
     }
 }
 
+/// Returns the device with the context of its error messages, `None` if the device has errors
 fn device_from_config(
     templates: &BTreeMap<String, DeviceConfig>,
     mut config: DeviceConfig,
-) -> Device {
+    index: usize,
+    errors: &mut Errors,
+) -> Option<(String, Device)> {
+    let num_errors = errors.len();
+
     // Use template if it exists
-    let mut c = config
+    let template = config
         .template
-        .and_then(|name| templates.get(&name).cloned())
-        .unwrap_or_default(); // All fields default to Option::None
+        .as_ref()
+        .map(|name| (name, templates.get(name)));
+    let mut c = template.and_then(|(_, t)| t.cloned()).unwrap_or_default(); // All fields default to Option::None
+
+    // e.g. "Device #2 (id `5`, template `meter`)"
+    let details: Vec<_> = config
+        .id
+        .or(c.id)
+        .map(|id| format!("id `{}`", id))
+        .into_iter()
+        .chain(template.map(|(name, _)| format!("template `{}`", name)))
+        .collect();
+    let mut context = format!("Device #{}", index + 1);
+    if !details.is_empty() {
+        context.push_str(&format!(" ({})", details.join(", ")));
+    }
+
+    if let Some((name, None)) = template {
+        errors.add(&context, format_args!("Unknown template `{}`", name));
+    }
 
     // Merge template and more specific config sections
-    let id =
-        c.id.xor(config.id)
-            .expect("Field `id`: Is it missing or defined both in template and device section?");
-    let scan_interval_str = c.scan_interval.xor(config.scan_interval).expect(
-        "Field `scan_interval`: Is it missing or defined both in template and device section?",
-    );
+    let id = c.id.xor(config.id);
+    if id.is_none() {
+        errors.add(
+            &context,
+            "Field `id`: Is it missing or defined both in template and device section?",
+        );
+    }
+    let scan_interval = match c.scan_interval.xor(config.scan_interval) {
+        Some(s) => match humantime::parse_duration(&s) {
+            Ok(d) if d == Duration::from_secs(0) => {
+                errors.add(&context, "`scan_interval` must not be zero");
+                None
+            }
+            Ok(d) => Some(d),
+            Err(e) => {
+                errors.add(
+                    &context,
+                    format_args!("Invalid `scan_interval` `{}`: {}", s, e),
+                );
+                None
+            }
+        },
+        None => {
+            errors.add(
+                &context,
+                "Field `scan_interval`: Is it missing or defined both in template and device section?",
+            );
+            None
+        }
+    };
     c.input_registers.append(&mut config.input_registers);
+    c.holding_registers.append(&mut config.holding_registers);
+    c.coils.append(&mut config.coils);
+    c.discrete_inputs.append(&mut config.discrete_inputs);
     c.tags.append(&mut config.tags);
+    c.forbidden_addresses
+        .append(&mut config.forbidden_addresses);
 
-    // Create a device from the merged config sections
-    Device::new(
-        id,
-        humantime::parse_duration(&scan_interval_str)
-            .unwrap_or_else(|_| panic!("Invalid `scan_interval` for device with id `{}`", id)),
-        c.tags.into_iter().collect(),
-        c.input_registers
-            .into_iter()
-            .map(|r| match r {
-                RegisterConfig::Simple(addr) => (
-                    addr,
-                    Register {
-                        name: format!("input_register_{}", addr),
-                        data_type: DataType::U16,
-                        scaling: 1.0,
-                        tags: BTreeMap::new(),
-                    },
-                ),
-                RegisterConfig::Advanced {
-                    addr,
-                    data_type,
-                    scaling,
-                    name,
-                    tags: register_tags,
-                } => (
-                    addr,
-                    Register {
-                        data_type: data_type
-                            .map(|t| {
-                                t.parse().unwrap_or_else(|_| {
-                                    panic!("`{}`: Invalid register type `{}`", &name, &t)
-                                })
-                            })
-                            .unwrap_or(DataType::U16),
-                        scaling: scaling.unwrap_or(1.0),
-                        name,
-                        tags: register_tags.into_iter().collect(),
-                    },
-                ),
-            })
-            .collect(),
-    )
-}
+    // Device specific byte order overwrites the template default
+    let byte_order = config
+        .byte_order
+        .or(c.byte_order)
+        .map(|b| parse_byte_order(&b, &context, errors))
+        .unwrap_or(ByteOrder::Abcd);
 
-#[derive(Clone, Default, Deserialize)]
-struct DeviceConfig {
-    template: Option<String>,
-    id: Option<u8>,
-    scan_interval: Option<String>,
+    // Registers without own outputs use the outputs of the device
+    let outputs = config.outputs.or(c.outputs);
 
-    #[serde(default)]
-    tags: BTreeMap<String, String>,
+    let max_registers_per_request = config
+        .max_registers_per_request
+        .or(c.max_registers_per_request);
+    if max_registers_per_request == Some(0) {
+        errors.add(&context, "`max_registers_per_request` must not be zero");
+    }
 
-    #[serde(default)]
-    input_registers: Vec<RegisterConfig>,
-}
+    let mut forbidden = Vec::new();
+    for range in &c.forbidden_addresses {
+        match range.parse() {
+            Some((start, end)) => forbidden.push((start..=end, range)),
+            None => errors.add(
+                &context,
+                format_args!("Invalid forbidden address range `{}`", range),
+            ),
+        }
+    }
 
-#[derive(Clone, Deserialize)]
-#[serde(untagged)]
-enum RegisterConfig {
-    Simple(u16),
-    Advanced {
-        addr: u16,
-        name: String,
+    let mut registers = BTreeMap::new();
+    for (reg_type, configs) in [
+        (RegisterType::InputRegister, c.input_registers),
+        (RegisterType::HoldingRegister, c.holding_registers),
+        (RegisterType::Coil, c.coils),
+        (RegisterType::DiscreteInput, c.discrete_inputs),
+    ] {
+        // A zero limit was reported above
+        let max_len = cmp::min(
+            reg_type.max_request_len(),
+            max_registers_per_request
+                .filter(|&n| n > 0)
+                .unwrap_or(u16::MAX),
+        );
+        registers.insert(
+            reg_type,
+            registers_from_config(
+                reg_type, configs, byte_order, &outputs, max_len, &context, errors,
+            ),
+        );
+    }
 
-        // #[serde(default)] does not work here because of parsing ambiguities
-        // https://github.com/serde-rs/serde/issues/368
-        // Workaround: Option and unwrap_or()
-        data_type: Option<String>,
-        scaling: Option<f64>,
+    // Forbidden addresses are only skipped in gaps, a register inside them would still be read
+    for (reg_type, map) in &registers {
+        for (addr, reg) in map {
+            let end = u32::from(*addr) + u32::from(reg.data_type.num_registers());
+            for (range, entry) in &forbidden {
+                if u32::from(*range.start()) < end && *range.end() >= *addr {
+                    errors.add(
+                        &context,
+                        format_args!(
+                            "{} `{}` overlaps the forbidden address range `{}`",
+                            reg_type, reg.name, entry
+                        ),
+                    );
+                }
+            }
+        }
+    }
 
-        #[serde(default)]
-        tags: BTreeMap<String, String>,
-    },
+    if errors.len() > num_errors {
+        return None;
+    }
+
+    // Create a device from the merged config sections
+    match Device::new(
+        config
+            .connection
+            .or(c.connection)
+            .unwrap_or_else(|| String::from(DEFAULT_CONNECTION)),
+        id?,
+        scan_interval?,
+        c.tags.into_iter().collect(),
+        registers,
+        RequestOptions {
+            max_len: max_registers_per_request,
+            max_gap: config.max_gap.or(c.max_gap).unwrap_or(0),
+            forbidden: forbidden.into_iter().map(|(range, _)| range).collect(),
+        },
+    ) {
+        Ok(device) => Some((context, device)),
+        Err(e) => {
+            errors.add(&context, e);
+            None
+        }
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::time::Duration;
+/// Falls back to the default byte order on errors
+fn parse_byte_order(byte_order: &str, context: &str, errors: &mut Errors) -> ByteOrder {
+    byte_order.parse().unwrap_or_else(|_| {
+        errors.add(context, format_args!("Invalid byte order `{}`", byte_order));
+        ByteOrder::Abcd
+    })
+}
 
-    #[test]
-    fn test_into_devices_simple() {
-        let dc: DevicesConfig = toml::from_str(
-            r#"
-            [[devices]]
+fn registers_from_config(
+    reg_type: RegisterType,
+    configs: Vec<RegisterConfig>,
+    default_byte_order: ByteOrder,
+    default_outputs: &Option<Vec<String>>,
+    max_len: u16,
+    context: &str,
+    errors: &mut Errors,
+) -> BTreeMap<u16, Register> {
+    let mut registers = BTreeMap::new();
+    for config in configs {
+        let (addr, reg) = register_from_config(
+            reg_type,
+            config,
+            default_byte_order,
+            default_outputs,
+            context,
+            errors,
+        );
+        match registers.get(&addr) {
+            Some(Register { name, .. }) => errors.add(
+                context,
+                format_args!(
+                    "{}s `{}` and `{}` use the same address {}",
+                    reg_type, name, reg.name, addr
+                ),
+            ),
+            None => {
+                registers.insert(addr, reg);
+            }
+        }
+    }
+
+    // Registers are sorted by address
+    let mut prev: Option<(u32, &str)> = None;
+    for (addr, reg) in &registers {
+        let start = u32::from(*addr);
+        let len = reg.data_type.num_registers();
+        let end = start + u32::from(len);
+        // A register is never split across requests
+        if len > max_len {
+            errors.add(
+                context,
+                format_args!(
+                    "{} `{}` is longer than the maximum request length {}",
+                    reg_type, reg.name, max_len
+                ),
+            );
+        }
+        // Requests store the exclusive end address as `u16`
+        if end > u32::from(u16::MAX) {
+            errors.add(
+                context,
+                format_args!("{} `{}` exceeds the address range", reg_type, reg.name),
+            );
+        }
+        match prev {
+            Some((prev_end, prev_name)) if start < prev_end => {
+                errors.add(
+                    context,
+                    format_args!("{}s `{}` and `{}` overlap", reg_type, prev_name, reg.name),
+                );
+                if end > prev_end {
+                    prev = Some((end, &reg.name));
+                }
+            }
+            _ => prev = Some((end, &reg.name)),
+        }
+    }
+
+    registers
+}
+
+/// Adds all problems of the register to `errors`, the returned register is not usable then.
+fn register_from_config(
+    reg_type: RegisterType,
+    config: RegisterConfig,
+    default_byte_order: ByteOrder,
+    default_outputs: &Option<Vec<String>>,
+    context: &str,
+    errors: &mut Errors,
+) -> (u16, Register) {
+    let is_bit = reg_type.is_bit();
+    // The byte order of the device does not apply to single bits
+    let default_byte_order = if is_bit {
+        ByteOrder::Abcd
+    } else {
+        default_byte_order
+    };
+
+    match config {
+        RegisterConfig::Simple(addr) => (
+            addr,
+            Register {
+                byte_order: default_byte_order,
+                outputs: default_outputs.clone(),
+                ..Register::new(format!("{}_{}", reg_type, addr), DataType::U16)
+            },
+        ),
+        RegisterConfig::Advanced {
+            addr,
+            data_type,
+            length,
+            trim,
+            byte_order,
+            scaling,
+            offset,
+            raw_range,
+            eng_range,
+            as_tag,
+            bits,
+            outputs,
+            name,
+            tags: register_tags,
+        } => {
+            let context = format!("{}: {} `{}`", context, reg_type, name);
+
+            if is_bit && data_type.is_some() {
+                errors.add(
+                    &context,
+                    "Field `data_type` is not allowed for coils and discrete inputs",
+                );
+            }
+            if is_bit && !bits.is_empty() {
+                errors.add(
+                    &context,
+                    "Field `bits` is not allowed for coils and discrete inputs",
+                );
+            }
+            if is_bit && byte_order.is_some() {
+                errors.add(
+                    &context,
+                    "Field `byte_order` is not allowed for coils and discrete inputs",
+                );
+            }
+
+            let data_type = match data_type.filter(|_| !is_bit).as_deref() {
+                Some("string") => match length {
+                    Some(len) if len > 0 => DataType::String {
+                        len,
+                        trim: trim.unwrap_or(true),
+                    },
+                    _ => {
+                        errors.add(&context, "Field `length` is required for strings");
+                        DataType::U16
+                    }
+                },
+                Some(t) => t.parse().unwrap_or_else(|_| {
+                    errors.add(&context, format_args!("Invalid data type `{}`", t));
+                    DataType::U16
+                }),
+                None => DataType::U16,
+            };
+
+            let as_tag = as_tag.unwrap_or(false);
+            if as_tag && !matches!(data_type, DataType::String { .. }) {
+                errors.add(&context, "Only strings can be used as tag");
+            }
+
+            // Two-point calibration is converted to scaling and offset
+            let (scaling, offset) = match (raw_range, eng_range) {
+                (None, None) => (scaling.unwrap_or(1.0), offset.unwrap_or(0.0)),
+                (Some([raw_min, raw_max]), Some([eng_min, eng_max]))
+                    if scaling.is_none() && offset.is_none() && raw_min != raw_max =>
+                {
+                    let scaling = (eng_max - eng_min) / (raw_max - raw_min);
+                    (scaling, eng_min - raw_min * scaling)
+                }
+                _ => {
+                    errors.add(
+                        &context,
+                        "Use either `raw_range` and `eng_range` or `scaling` and `offset`",
+                    );
+                    (1.0, 0.0)
+                }
+            };
+
+            let bits = bits
+                .into_iter()
+                .filter_map(|(bits_name, b)| match bits_from_config(&b, data_type) {
+                    Some(bits) => Some((bits_name, bits)),
+                    None => {
+                        errors.add(&context, format_args!("Invalid bits `{}`", bits_name));
+                        None
+                    }
+                })
+                .collect();
+
+            (
+                addr,
+                Register {
+                    data_type,
+                    byte_order: byte_order
+                        .filter(|_| !is_bit)
+                        .map(|b| parse_byte_order(&b, &context, errors))
+                        .unwrap_or(default_byte_order),
+                    scaling,
+                    offset,
+                    as_tag,
+                    bits,
+                    outputs: outputs.or_else(|| default_outputs.clone()),
+                    name,
+                    tags: register_tags.into_iter().collect(),
+                },
+            )
+        }
+    }
+}
+
+/// Returns `None` if the bits are not inside of the register
+fn bits_from_config(config: &RangeConfig, data_type: DataType) -> Option<Bits> {
+    let (start, end) = config.parse()?;
+
+    let is_integer = !matches!(
+        data_type,
+        DataType::F32 | DataType::F64 | DataType::String { .. }
+    );
+    let num_bits = 16 * data_type.num_registers();
+    if is_integer && end < num_bits {
+        Bits::new(start as u8, end as u8)
+    } else {
+        None
+    }
+}
+
+#[derive(Clone, Default, Deserialize)]
+struct DeviceConfig {
+    template: Option<String>,
+    connection: Option<String>,
+    id: Option<u8>,
+    scan_interval: Option<String>,
+    byte_order: Option<String>,
+    max_registers_per_request: Option<u16>,
+    max_gap: Option<u16>,
+    outputs: Option<Vec<String>>,
+
+    #[serde(default)]
+    forbidden_addresses: Vec<RangeConfig>,
+
+    #[serde(default)]
+    tags: BTreeMap<String, String>,
+
+    #[serde(default)]
+    input_registers: Vec<RegisterConfig>,
+
+    #[serde(default)]
+    holding_registers: Vec<RegisterConfig>,
+
+    #[serde(default)]
+    coils: Vec<RegisterConfig>,
+
+    #[serde(default)]
+    discrete_inputs: Vec<RegisterConfig>,
+}
+
+// Only used while loading the configuration, the size does not matter
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Deserialize)]
+#[serde(untagged)]
+enum RegisterConfig {
+    Simple(u16),
+    Advanced {
+        addr: u16,
+        name: String,
+
+        // #[serde(default)] does not work here because of parsing ambiguities
+        // https://github.com/serde-rs/serde/issues/368
+        // Workaround: Option and unwrap_or()
+        data_type: Option<String>,
+        length: Option<u16>,
+        trim: Option<bool>,
+        byte_order: Option<String>,
+        scaling: Option<f64>,
+        offset: Option<f64>,
+        raw_range: Option<[f64; 2]>,
+        eng_range: Option<[f64; 2]>,
+        as_tag: Option<bool>,
+        outputs: Option<Vec<String>>,
+
+        #[serde(default)]
+        bits: BTreeMap<String, RangeConfig>,
+
+        #[serde(default)]
+        tags: BTreeMap<String, String>,
+    },
+}
+
+/// A single number or an inclusive range like "4..7" (TOML arrays can not mix types,
+/// so single numbers are also accepted as string)
+#[derive(Clone, Deserialize)]
+#[serde(untagged)]
+enum RangeConfig {
+    Single(u16),
+    Range(String),
+}
+
+impl fmt::Display for RangeConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RangeConfig::Single(n) => write!(f, "{}", n),
+            RangeConfig::Range(range) => write!(f, "{}", range),
+        }
+    }
+}
+
+impl RangeConfig {
+    fn parse(&self) -> Option<(u16, u16)> {
+        let (start, end) = match self {
+            RangeConfig::Single(n) => (*n, *n),
+            RangeConfig::Range(range) => {
+                let mut parts = range.splitn(2, "..");
+                let start = parts.next()?.trim().parse().ok()?;
+                match parts.next() {
+                    Some(end) => (start, end.trim().parse().ok()?),
+                    None => (start, start),
+                }
+            }
+        };
+
+        if start <= end {
+            Some((start, end))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn input_registers(
+        registers: BTreeMap<u16, Register>,
+    ) -> BTreeMap<RegisterType, BTreeMap<u16, Register>> {
+        let mut map = BTreeMap::new();
+        map.insert(RegisterType::InputRegister, registers);
+        map
+    }
+
+    #[test]
+    fn test_modbus_config() {
+        let mc: ModbusConfig = toml::from_str(
+            r#"
+            hostname = "127.0.0.1"
+            port = 502
+            timeout = "1s"
+            "#,
+        )
+        .unwrap();
+        assert!(matches!(mc.transport, TransportConfig::Tcp { .. }));
+        assert_eq!(mc.reconnect_max_delay().unwrap(), Duration::from_secs(60));
+
+        let mc: ModbusConfig = toml::from_str(
+            r#"
+            device = "/dev/ttyUSB0"
+            baud_rate = 9600
+            timeout = "1s"
+            reconnect_max_delay = "5min"
+            "#,
+        )
+        .unwrap();
+        assert!(matches!(mc.transport, TransportConfig::Rtu { .. }));
+        assert_eq!(mc.reconnect_max_delay().unwrap(), Duration::from_secs(300));
+    }
+
+    #[test]
+    fn test_influxdb_config() {
+        let ic: InfluxDbConfig = toml::from_str(
+            r#"
+            hostname = "http://localhost:8086"
+            database = "testdb"
+            "#,
+        )
+        .unwrap();
+        assert!(ic.buffer.is_none());
+        assert_eq!(ic.queue_size(), 1000);
+        assert_eq!(ic.overflow().unwrap(), Overflow::DropOldest);
+        assert_eq!(ic.batch_size(), 5000);
+        assert_eq!(ic.flush_interval().unwrap(), Duration::from_secs(0));
+        assert!(!ic.gzip());
+        assert_eq!(
+            ic.to_request(()).uri(),
+            "http://localhost:8086/write?db=testdb"
+        );
+
+        let ic: InfluxDbConfig = toml::from_str(
+            r#"
+            hostname = "http://localhost:9999/api/v2"
+            organization = "testorg"
+            bucket = "testbucket"
+            auth_token = "abc"
+            overflow = "spill"
+            flush_interval = "10s"
+            gzip = true
+            buffer.path = "/var/lib/data-collector"
+            "#,
+        )
+        .unwrap();
+        let req = ic.to_request(());
+        assert_eq!(
+            req.uri(),
+            "http://localhost:9999/api/v2/write?org=testorg&bucket=testbucket"
+        );
+        assert_eq!(req.headers()["Authorization"], "Token abc");
+        assert_eq!(ic.overflow().unwrap(), Overflow::Spill);
+        assert_eq!(ic.flush_interval().unwrap(), Duration::from_secs(10));
+        assert!(ic.gzip());
+        let buffer = ic.buffer.unwrap();
+        assert_eq!(buffer.path, "/var/lib/data-collector");
+        assert_eq!(buffer.max_size(), 100_000_000);
+    }
+
+    #[test]
+    fn test_mqtt_config() {
+        let mc: MqttConfig = toml::from_str(
+            r#"
+            hostname = "localhost"
+            topic = "sensors/{id}/{name}"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(mc.port(), 1883);
+        assert_eq!(mc.client_id(), "data-collector");
+        assert_eq!(mc.format().unwrap(), MqttFormat::Value);
+        assert_eq!(mc.qos().unwrap(), QoS::AtMostOnce);
+        assert!(!mc.retain());
+
+        let mc: MqttConfig = toml::from_str(
+            r#"
+            hostname = "broker.example.com"
+            topic = "sensors/{id}"
+            format = "json"
+            qos = 1
+            tls = true
+            "#,
+        )
+        .unwrap();
+        assert_eq!(mc.port(), 8883);
+        assert_eq!(mc.format().unwrap(), MqttFormat::Json);
+        assert_eq!(mc.qos().unwrap(), QoS::AtLeastOnce);
+    }
+
+    #[test]
+    fn test_unchecked_config_errors() {
+        let mc: ModbusConfig = toml::from_str(
+            r#"
+            device = "/dev/ttyUSB0"
+            baud_rate = 9600
+            parity = "mark"
+            timeout = "1 parsec"
+            reconnect_max_delay = "soon"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            mc.connect().err().unwrap().to_string(),
+            "I/O error: Invalid `timeout` `1 parsec`: unknown unit at 2-8"
+        );
+        assert_eq!(
+            mc.connect_with_timeout(Duration::from_secs(1))
+                .err()
+                .unwrap()
+                .to_string(),
+            "I/O error: Invalid `parity` `mark`: Use \"none\", \"even\" or \"odd\""
+        );
+        assert_eq!(
+            mc.reconnect_max_delay().unwrap_err().to_string(),
+            "Invalid `reconnect_max_delay` `soon`: expected number at 0"
+        );
+
+        let ic: InfluxDbConfig = toml::from_str(
+            r#"
+            hostname = "http://localhost:8086"
+            database = "testdb"
+            overflow = "wait"
+            flush_interval = "later"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            ic.overflow().unwrap_err().to_string(),
+            "Invalid `overflow` `wait`: Use \"drop_oldest\", \"block\" or \"spill\""
+        );
+        assert_eq!(
+            ic.flush_interval().unwrap_err().to_string(),
+            "Invalid `flush_interval` `later`: expected number at 0"
+        );
+
+        let mc: MqttConfig = toml::from_str(
+            r#"
+            hostname = "localhost"
+            topic = "sensors/{id}"
+            format = "xml"
+            qos = 3
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            mc.format().unwrap_err().to_string(),
+            "Invalid `format` `xml`: Use \"value\" or \"json\""
+        );
+        assert_eq!(
+            mc.qos().unwrap_err().to_string(),
+            "Invalid `qos` `3`: Use 0, 1 or 2"
+        );
+    }
+
+    #[test]
+    fn test_output_config() {
+        let outputs: BTreeMap<String, OutputConfig> = toml::from_str(
+            r#"
+            [local]
+            type = "influxdb"
+            hostname = "http://localhost:8086"
+            database = "db"
+
+            [cloud]
+            type = "influxdb2"
+            hostname = "https://cloud.example.com"
+            organization = "org"
+            bucket = "bucket"
+            auth_token = "token"
+
+            [alarms]
+            type = "mqtt"
+            hostname = "localhost"
+            topic = "alarms/{name}"
+            "#,
+        )
+        .unwrap();
+        assert!(matches!(outputs["local"], OutputConfig::InfluxDb(_)));
+        assert!(matches!(outputs["cloud"], OutputConfig::InfluxDb(_)));
+        assert!(matches!(outputs["alarms"], OutputConfig::Mqtt(_)));
+    }
+
+    #[test]
+    fn test_into_devices_simple() {
+        let dc: DevicesConfig = toml::from_str(
+            r#"
+            [[devices]]
             id = 1
             scan_interval = "1s"
             input_registers = [1, 1234]
@@ -226,32 +1372,19 @@ mod tests {
         .unwrap();
 
         let mut registers = BTreeMap::new();
-        registers.insert(
-            1,
-            Register {
-                name: String::from("input_register_1"),
-                tags: BTreeMap::new(),
-                data_type: DataType::U16,
-                scaling: 1.0,
-            },
-        );
-        registers.insert(
-            1234,
-            Register {
-                name: String::from("input_register_1234"),
-                tags: BTreeMap::new(),
-                data_type: DataType::U16,
-                scaling: 1.0,
-            },
-        );
+        registers.insert(1, Register::new("input_register_1", DataType::U16));
+        registers.insert(1234, Register::new("input_register_1234", DataType::U16));
 
         let devices = vec![Device::new(
+            String::from(DEFAULT_CONNECTION),
             1,
             Duration::from_secs(1),
             BTreeMap::new(),
-            registers,
-        )];
-        assert_eq!(dc.into_devices(), devices);
+            input_registers(registers),
+            RequestOptions::default(),
+        )
+        .unwrap()];
+        assert_eq!(dc.into_devices().unwrap(), devices);
     }
 
     #[test]
@@ -270,7 +1403,7 @@ mod tests {
             tags.foo = "bar"
 
             [[devices.input_registers]]
-            addr = 2
+            addr = 3
             name = "quxbaz"
             "#,
         )
@@ -283,29 +1416,23 @@ mod tests {
         registers.insert(
             1,
             Register {
-                name: String::from("foobar"),
                 tags,
-                data_type: DataType::F32,
                 scaling: 8.7,
+                ..Register::new("foobar", DataType::F32)
             },
         );
-        registers.insert(
-            2,
-            Register {
-                name: String::from("quxbaz"),
-                tags: BTreeMap::new(),
-                data_type: DataType::U16,
-                scaling: 1.0,
-            },
-        );
+        registers.insert(3, Register::new("quxbaz", DataType::U16));
 
         let devices = vec![Device::new(
+            String::from(DEFAULT_CONNECTION),
             1,
             Duration::from_secs(1),
             BTreeMap::new(),
-            registers,
-        )];
-        assert_eq!(dc.into_devices(), devices);
+            input_registers(registers),
+            RequestOptions::default(),
+        )
+        .unwrap()];
+        assert_eq!(dc.into_devices().unwrap(), devices);
     }
 
     #[test]
@@ -340,19 +1467,715 @@ mod tests {
         registers.insert(
             1,
             Register {
-                name: String::from("quxbaz"),
                 tags: register_tags,
-                data_type: DataType::U16,
-                scaling: 1.0,
+                ..Register::new("quxbaz", DataType::U16)
             },
         );
 
         let devices = vec![Device::new(
+            String::from(DEFAULT_CONNECTION),
             1,
             Duration::from_secs(1),
             device_tags,
+            input_registers(registers),
+            RequestOptions::default(),
+        )
+        .unwrap()];
+        assert_eq!(dc.into_devices().unwrap(), devices);
+    }
+
+    #[test]
+    fn test_into_devices_register_types() {
+        let dc: DevicesConfig = toml::from_str(
+            r#"
+            [[devices]]
+            id = 1
+            scan_interval = "1s"
+            holding_registers = [2]
+            coils = [3]
+
+            [[devices.discrete_inputs]]
+            addr = 4
+            name = "door_open"
+            "#,
+        )
+        .unwrap();
+
+        let mut holding_registers = BTreeMap::new();
+        holding_registers.insert(2, Register::new("holding_register_2", DataType::U16));
+        let mut coils = BTreeMap::new();
+        coils.insert(3, Register::new("coil_3", DataType::U16));
+        let mut discrete_inputs = BTreeMap::new();
+        discrete_inputs.insert(4, Register::new("door_open", DataType::U16));
+
+        let mut registers = BTreeMap::new();
+        registers.insert(RegisterType::HoldingRegister, holding_registers);
+        registers.insert(RegisterType::Coil, coils);
+        registers.insert(RegisterType::DiscreteInput, discrete_inputs);
+
+        let devices = vec![Device::new(
+            String::from(DEFAULT_CONNECTION),
+            1,
+            Duration::from_secs(1),
+            BTreeMap::new(),
             registers,
-        )];
-        assert_eq!(dc.into_devices(), devices);
+            RequestOptions::default(),
+        )
+        .unwrap()];
+        assert_eq!(dc.into_devices().unwrap(), devices);
+    }
+
+    #[test]
+    fn test_into_devices_bit_errors() {
+        let dc: DevicesConfig = toml::from_str(
+            r#"
+            [[devices]]
+            id = 1
+            scan_interval = "1s"
+
+            [[devices.coils]]
+            addr = 1
+            name = "pump"
+            data_type = "f64"
+
+            [[devices.discrete_inputs]]
+            addr = 2
+            name = "status"
+            bits = { door_open = 0, fault_code = "0..15" }
+
+            [[devices.discrete_inputs]]
+            addr = 3
+            name = "alarm"
+            byte_order = "BADC"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            dc.into_devices().unwrap_err().0,
+            vec![
+                "Device #1 (id `1`): coil `pump`: Field `data_type` is not allowed for coils and discrete inputs",
+                "Device #1 (id `1`): discrete_input `status`: Field `bits` is not allowed for coils and discrete inputs",
+                "Device #1 (id `1`): discrete_input `alarm`: Field `byte_order` is not allowed for coils and discrete inputs",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_into_devices_bit_byte_order() {
+        let dc: DevicesConfig = toml::from_str(
+            r#"
+            [[devices]]
+            id = 1
+            scan_interval = "1s"
+            byte_order = "BADC"
+            coils = [0]
+            input_registers = [0]
+            "#,
+        )
+        .unwrap();
+
+        let mut coils = BTreeMap::new();
+        coils.insert(0, Register::new("coil_0", DataType::U16));
+        let mut registers = BTreeMap::new();
+        registers.insert(
+            0,
+            Register {
+                byte_order: ByteOrder::Badc,
+                ..Register::new("input_register_0", DataType::U16)
+            },
+        );
+        let mut registers = input_registers(registers);
+        registers.insert(RegisterType::Coil, coils);
+
+        let devices = vec![Device::new(
+            String::from(DEFAULT_CONNECTION),
+            1,
+            Duration::from_secs(1),
+            BTreeMap::new(),
+            registers,
+            RequestOptions::default(),
+        )
+        .unwrap()];
+        assert_eq!(dc.into_devices().unwrap(), devices);
+    }
+
+    #[test]
+    fn test_into_devices_connection() {
+        let dc: DevicesConfig = toml::from_str(
+            r#"
+            [templates.foobar]
+            connection = "gw1"
+            scan_interval = "1s"
+
+            [[devices]]
+            template = "foobar"
+            id = 1
+
+            [[devices]]
+            template = "foobar"
+            connection = "gw2"
+            id = 2
+
+            [[devices]]
+            id = 3
+            scan_interval = "1s"
+            "#,
+        )
+        .unwrap();
+
+        let connections: Vec<_> = dc
+            .into_devices()
+            .unwrap()
+            .into_iter()
+            .map(|d| d.connection)
+            .collect();
+        assert_eq!(connections, vec!["gw1", "gw2", DEFAULT_CONNECTION]);
+    }
+
+    #[test]
+    fn test_into_devices_outputs() {
+        let dc: DevicesConfig = toml::from_str(
+            r#"
+            [templates.foobar]
+            scan_interval = "1s"
+            outputs = ["local", "cloud"]
+
+            [[devices]]
+            template = "foobar"
+            id = 1
+            holding_registers = [1]
+
+            [[devices.input_registers]]
+            addr = 2
+            name = "alarm"
+            outputs = ["alarms"]
+
+            [[devices]]
+            template = "foobar"
+            id = 2
+            outputs = ["local"]
+            coils = [1]
+
+            [[devices]]
+            id = 3
+            scan_interval = "1s"
+            input_registers = [1]
+            "#,
+        )
+        .unwrap();
+
+        let outputs: Vec<Vec<_>> = dc
+            .into_devices()
+            .unwrap()
+            .iter()
+            .map(|d| d.outputs().map(String::from).collect())
+            .collect();
+        assert_eq!(
+            outputs,
+            vec![
+                vec!["alarms", "local", "cloud"],
+                vec!["local"],
+                Vec::<&str>::new(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_into_devices_errors() {
+        let dc: DevicesConfig = toml::from_str(
+            r#"
+            [templates.meter]
+            scan_interval = "1s"
+
+            [[templates.meter.input_registers]]
+            addr = 1
+            name = "energy"
+            data_type = "u32"
+
+            [[devices]]
+            template = "meter"
+            id = 1
+            input_registers = [2]
+
+            [[devices]]
+            template = "unknown"
+            scan_interval = "fast"
+
+            [[devices]]
+            id = 3
+            scan_interval = "1s"
+            coils = [4, 4]
+
+            [[devices.input_registers]]
+            addr = 1
+            name = "power"
+            data_type = "float"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            dc.into_devices().unwrap_err().0,
+            vec![
+                "Device #1 (id `1`, template `meter`): input_registers `energy` and `input_register_2` overlap",
+                "Device #2 (template `unknown`): Unknown template `unknown`",
+                "Device #2 (template `unknown`): Field `id`: Is it missing or defined both in template and device section?",
+                "Device #2 (template `unknown`): Invalid `scan_interval` `fast`: expected number at 0",
+                "Device #3 (id `3`): input_register `power`: Invalid data type `float`",
+                "Device #3 (id `3`): coils `coil_4` and `coil_4` use the same address 4",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_into_setup_connection_only() {
+        let config: Config = toml::from_str(
+            r#"
+            [modbus]
+            hostname = "127.0.0.1"
+            port = 502
+            timeout = "1s"
+            "#,
+        )
+        .unwrap();
+
+        let setup = config.into_setup().unwrap();
+        assert!(setup.connections.contains_key(DEFAULT_CONNECTION));
+        assert_eq!(
+            setup.check_collecting().unwrap_err().to_string(),
+            "Devices: None configured, add a `[[devices]]` section\n\
+             Outputs: None configured, add an `[outputs.<output_name>]` section"
+        );
+    }
+
+    #[test]
+    fn test_into_setup_errors() {
+        let config: Config = toml::from_str(
+            r#"
+            [modbus]
+            hostname = "127.0.0.1"
+            port = 502
+            timeout = "1 parsec"
+
+            [connections.bus]
+            device = "/dev/ttyUSB0"
+            baud_rate = 0
+            timeout = "1s"
+
+            [outputs.local]
+            type = "influxdb"
+            hostname = "http://localhost:8086"
+            database = "db"
+            overflow = "spill"
+
+            [[devices]]
+            id = 1
+            scan_interval = "1s"
+            connection = "gw1"
+            outputs = ["cloud"]
+            input_registers = [1]
+            "#,
+        )
+        .unwrap();
+
+        let errors = config.into_setup().err().unwrap();
+        assert_eq!(
+            errors.to_string(),
+            "Connection `bus`: `baud_rate` must not be zero\n\
+             Connection `modbus`: Invalid `timeout` `1 parsec`: unknown unit at 2-8\n\
+             Output `local`: Overflow policy `spill` requires a buffer\n\
+             Device #1 (id `1`): Unknown connection `gw1`\n\
+             Device #1 (id `1`): Unknown output `cloud`"
+        );
+    }
+
+    #[test]
+    fn test_into_devices_byte_order() {
+        let dc: DevicesConfig = toml::from_str(
+            r#"
+            [templates.foobar]
+            scan_interval = "1s"
+            byte_order = "CDAB"
+
+            [[templates.foobar.input_registers]]
+            addr = 2
+            name = "quxbaz"
+            data_type = "f32"
+            byte_order = "DCBA"
+
+            [[devices]]
+            template = "foobar"
+            id = 1
+            input_registers = [1]
+
+            [[devices]]
+            template = "foobar"
+            id = 2
+            byte_order = "BADC"
+            input_registers = [1]
+            "#,
+        )
+        .unwrap();
+
+        let registers = |default_byte_order| {
+            let mut registers = BTreeMap::new();
+            registers.insert(
+                1,
+                Register {
+                    byte_order: default_byte_order,
+                    ..Register::new("input_register_1", DataType::U16)
+                },
+            );
+            registers.insert(
+                2,
+                Register {
+                    byte_order: ByteOrder::Dcba,
+                    ..Register::new("quxbaz", DataType::F32)
+                },
+            );
+            input_registers(registers)
+        };
+
+        let devices = vec![
+            Device::new(
+                String::from(DEFAULT_CONNECTION),
+                1,
+                Duration::from_secs(1),
+                BTreeMap::new(),
+                registers(ByteOrder::Cdab),
+                RequestOptions::default(),
+            )
+            .unwrap(),
+            Device::new(
+                String::from(DEFAULT_CONNECTION),
+                2,
+                Duration::from_secs(1),
+                BTreeMap::new(),
+                registers(ByteOrder::Badc),
+                RequestOptions::default(),
+            )
+            .unwrap(),
+        ];
+        assert_eq!(dc.into_devices().unwrap(), devices);
+    }
+
+    #[test]
+    fn test_into_devices_string() {
+        let dc: DevicesConfig = toml::from_str(
+            r#"
+            [[devices]]
+            id = 1
+            scan_interval = "1s"
+
+            [[devices.holding_registers]]
+            addr = 1
+            name = "serial_number"
+            data_type = "string"
+            length = 8
+            as_tag = true
+            "#,
+        )
+        .unwrap();
+
+        let mut holding_registers = BTreeMap::new();
+        holding_registers.insert(
+            1,
+            Register {
+                as_tag: true,
+                ..Register::new("serial_number", DataType::String { len: 8, trim: true })
+            },
+        );
+        let mut registers = BTreeMap::new();
+        registers.insert(RegisterType::HoldingRegister, holding_registers);
+
+        let devices = vec![Device::new(
+            String::from(DEFAULT_CONNECTION),
+            1,
+            Duration::from_secs(1),
+            BTreeMap::new(),
+            registers,
+            RequestOptions::default(),
+        )
+        .unwrap()];
+        assert_eq!(dc.into_devices().unwrap(), devices);
+    }
+
+    #[test]
+    fn test_into_devices_bits() {
+        let dc: DevicesConfig = toml::from_str(
+            r#"
+            [[devices]]
+            id = 1
+            scan_interval = "1s"
+
+            [[devices.input_registers]]
+            addr = 1
+            name = "status"
+            bits = { door_open = 0, fault_code = "4..7" }
+            "#,
+        )
+        .unwrap();
+
+        let mut bits = BTreeMap::new();
+        bits.insert(String::from("door_open"), Bits::new(0, 0).unwrap());
+        bits.insert(String::from("fault_code"), Bits::new(4, 7).unwrap());
+
+        let mut registers = BTreeMap::new();
+        registers.insert(
+            1,
+            Register {
+                bits,
+                ..Register::new("status", DataType::U16)
+            },
+        );
+
+        let devices = vec![Device::new(
+            String::from(DEFAULT_CONNECTION),
+            1,
+            Duration::from_secs(1),
+            BTreeMap::new(),
+            input_registers(registers),
+            RequestOptions::default(),
+        )
+        .unwrap()];
+        assert_eq!(dc.into_devices().unwrap(), devices);
+    }
+
+    #[test]
+    fn test_bits_from_config() {
+        let range = |r: &str| RangeConfig::Range(String::from(r));
+        assert_eq!(
+            bits_from_config(&range("4..7"), DataType::U16),
+            Bits::new(4, 7)
+        );
+        assert_eq!(
+            bits_from_config(&RangeConfig::Single(31), DataType::I32),
+            Bits::new(31, 31)
+        );
+        assert_eq!(bits_from_config(&range("8..16"), DataType::U16), None);
+        assert_eq!(bits_from_config(&range("7..4"), DataType::U16), None);
+        assert_eq!(
+            bits_from_config(&range("4"), DataType::U16),
+            Bits::new(4, 4)
+        );
+        assert_eq!(bits_from_config(&range("4.."), DataType::U16), None);
+        assert_eq!(
+            bits_from_config(&RangeConfig::Single(0), DataType::F32),
+            None
+        );
+    }
+
+    #[test]
+    fn test_into_devices_offset_and_range() {
+        let dc: DevicesConfig = toml::from_str(
+            r#"
+            [templates.transmitter]
+            scan_interval = "1s"
+
+            [[templates.transmitter.input_registers]]
+            addr = 1
+            name = "pressure"
+            raw_range = [4000, 20000]
+            eng_range = [0, 16]
+
+            [[templates.transmitter.input_registers]]
+            addr = 2
+            name = "temperature"
+            scaling = 0.1
+            offset = -40
+
+            [[devices]]
+            template = "transmitter"
+            id = 1
+            "#,
+        )
+        .unwrap();
+
+        let mut registers = BTreeMap::new();
+        registers.insert(
+            1,
+            Register {
+                scaling: 0.001,
+                offset: -4.0,
+                ..Register::new("pressure", DataType::U16)
+            },
+        );
+        registers.insert(
+            2,
+            Register {
+                scaling: 0.1,
+                offset: -40.0,
+                ..Register::new("temperature", DataType::U16)
+            },
+        );
+
+        let devices = vec![Device::new(
+            String::from(DEFAULT_CONNECTION),
+            1,
+            Duration::from_secs(1),
+            BTreeMap::new(),
+            input_registers(registers),
+            RequestOptions::default(),
+        )
+        .unwrap()];
+        assert_eq!(dc.into_devices().unwrap(), devices);
+    }
+
+    #[test]
+    fn test_into_devices_max_registers_per_request() {
+        let dc: DevicesConfig = toml::from_str(
+            r#"
+            [[devices]]
+            id = 1
+            scan_interval = "1s"
+            max_registers_per_request = 1
+            input_registers = [1, 2]
+            "#,
+        )
+        .unwrap();
+
+        let mut registers = BTreeMap::new();
+        for addr in 1..=2 {
+            registers.insert(
+                addr,
+                Register::new(format!("input_register_{}", addr), DataType::U16),
+            );
+        }
+
+        let device = |max_len| {
+            Device::new(
+                String::from(DEFAULT_CONNECTION),
+                1,
+                Duration::from_secs(1),
+                BTreeMap::new(),
+                input_registers(registers.clone()),
+                RequestOptions {
+                    max_len,
+                    ..RequestOptions::default()
+                },
+            )
+            .unwrap()
+        };
+        let devices = dc.into_devices().unwrap();
+        assert_ne!(devices, vec![device(None)]);
+        assert_eq!(devices, vec![device(Some(1))]);
+    }
+
+    #[test]
+    fn test_into_devices_request_len_errors() {
+        let dc: DevicesConfig = toml::from_str(
+            r#"
+            [[devices]]
+            id = 1
+            scan_interval = "1s"
+
+            [[devices.input_registers]]
+            addr = 10
+            name = "serial"
+            data_type = "string"
+            length = 200
+
+            [[devices]]
+            id = 2
+            scan_interval = "1s"
+            max_registers_per_request = 1
+
+            [[devices.holding_registers]]
+            addr = 0
+            name = "setpoint"
+            data_type = "f32"
+
+            [[devices]]
+            id = 3
+            scan_interval = "1s"
+            max_registers_per_request = 0
+            coils = [1]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            dc.into_devices().unwrap_err().0,
+            vec![
+                "Device #1 (id `1`): input_register `serial` is longer than the maximum request length 125",
+                "Device #2 (id `2`): holding_register `setpoint` is longer than the maximum request length 1",
+                "Device #3 (id `3`): `max_registers_per_request` must not be zero",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_into_devices_max_gap() {
+        let dc: DevicesConfig = toml::from_str(
+            r#"
+            [templates.foobar]
+            scan_interval = "1s"
+            max_gap = 10
+            forbidden_addresses = ["4", "5..6"]
+
+            [[devices]]
+            template = "foobar"
+            id = 1
+            input_registers = [0, 2, 8]
+            "#,
+        )
+        .unwrap();
+
+        let mut registers = BTreeMap::new();
+        for addr in &[0, 2, 8] {
+            registers.insert(
+                *addr,
+                Register::new(format!("input_register_{}", addr), DataType::U16),
+            );
+        }
+
+        let device = |forbidden| {
+            Device::new(
+                String::from(DEFAULT_CONNECTION),
+                1,
+                Duration::from_secs(1),
+                BTreeMap::new(),
+                input_registers(registers.clone()),
+                RequestOptions {
+                    max_len: None,
+                    max_gap: 10,
+                    forbidden,
+                },
+            )
+            .unwrap()
+        };
+        let devices = dc.into_devices().unwrap();
+        assert_ne!(devices, vec![device(vec![])]);
+        assert_eq!(devices, vec![device(vec![4..=4, 5..=6])]);
+    }
+
+    #[test]
+    fn test_into_devices_forbidden_errors() {
+        let dc: DevicesConfig = toml::from_str(
+            r#"
+            [[devices]]
+            id = 1
+            scan_interval = "1s"
+            forbidden_addresses = ["4", "6..7", "9..8"]
+            coils = [6]
+            holding_registers = [0, 5]
+
+            [[devices.input_registers]]
+            addr = 3
+            name = "power"
+            data_type = "f32"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            dc.into_devices().unwrap_err().0,
+            vec![
+                "Device #1 (id `1`): Invalid forbidden address range `9..8`",
+                "Device #1 (id `1`): coil `coil_6` overlaps the forbidden address range `6..7`",
+                "Device #1 (id `1`): input_register `power` overlaps the forbidden address range `4`",
+            ]
+        );
     }
 }
@@ -1,9 +1,13 @@
+use std::borrow::Cow;
+use std::cmp;
 use std::collections::BTreeMap;
-use std::iter;
+use std::fmt;
+use std::ops::{Range, RangeInclusive};
 use std::str::FromStr;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use modbus::{Client, Error};
+use derive_more::Display;
+use modbus::{Client, Coil, Error};
 
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum DataType {
@@ -13,6 +17,14 @@ pub enum DataType {
     I32,
     F32,
     F64,
+    U64,
+    I64,
+    /// ASCII characters, two per register
+    String {
+        len: u16,
+        /// Remove NUL and space padding
+        trim: bool,
+    },
 }
 
 impl FromStr for DataType {
@@ -26,17 +38,42 @@ impl FromStr for DataType {
             "i32" => Ok(Self::I32),
             "f32" => Ok(Self::F32),
             "f64" => Ok(Self::F64),
+            "u64" => Ok(Self::U64),
+            "i64" => Ok(Self::I64),
             _ => Err(()),
         }
     }
 }
 
+impl fmt::Display for DataType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::U16 => write!(f, "u16"),
+            Self::U32 => write!(f, "u32"),
+            Self::I16 => write!(f, "i16"),
+            Self::I32 => write!(f, "i32"),
+            Self::F32 => write!(f, "f32"),
+            Self::F64 => write!(f, "f64"),
+            Self::U64 => write!(f, "u64"),
+            Self::I64 => write!(f, "i64"),
+            Self::String { len, trim } => {
+                write!(f, "string, length {}", len)?;
+                if !trim {
+                    write!(f, ", not trimmed")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
 impl DataType {
-    fn num_registers(self) -> u16 {
+    pub fn num_registers(self) -> u16 {
         match self {
             Self::U16 | Self::I16 => 1,
             Self::U32 | Self::I32 | Self::F32 => 2,
-            Self::F64 => 4,
+            Self::F64 | Self::U64 | Self::I64 => 4,
+            Self::String { len, .. } => len,
         }
     }
 
@@ -53,42 +90,369 @@ impl DataType {
                     | (data[2] as u64) << 16
                     | data[3] as u64,
             ),
+            Self::U64 => parse_u64(data) as f64,
+            Self::I64 => parse_u64(data) as i64 as f64,
+            // Strings do not have a numeric representation
+            Self::String { .. } => f64::NAN,
+        }
+    }
+
+    /// Like `parse_data()` but keeps 64bit integers exact and supports strings.
+    pub fn parse_value(self, data: &[u16]) -> Value {
+        match self {
+            Self::U64 => Value::UInt(parse_u64(data)),
+            Self::I64 => Value::Int(parse_u64(data) as i64),
+            Self::String { len, trim } => {
+                let bytes: Vec<u8> = data[..len as usize]
+                    .iter()
+                    .flat_map(|word| word.to_be_bytes().to_vec())
+                    .collect();
+                let s = String::from_utf8_lossy(&bytes);
+                if trim {
+                    Value::String(s.trim_matches(|c| c == '\0' || c == ' ').to_string())
+                } else {
+                    Value::String(s.into_owned())
+                }
+            }
+            _ => Value::Float(self.parse_data(data)),
+        }
+    }
+
+    /// Inverse of `parse_value()`. Numbers are rounded and saturated for integer types,
+    /// strings are padded with NUL characters.
+    pub fn to_data(self, value: &Value) -> Vec<u16> {
+        let words =
+            |bits: u64, len: u32| (0..len).rev().map(|i| (bits >> (16 * i)) as u16).collect();
+        let v = match value {
+            Value::Float(v) => *v,
+            Value::Int(v) => *v as f64,
+            Value::UInt(v) => *v as f64,
+            Value::Bool(v) => f64::from(u8::from(*v)),
+            Value::String(_) => 0.0,
+        };
+
+        match (self, value) {
+            (Self::U16, _) => vec![v.round() as u16],
+            (Self::I16, _) => vec![v.round() as i16 as u16],
+            (Self::U32, _) => words(u64::from(v.round() as u32), 2),
+            (Self::I32, _) => words(u64::from(v.round() as i32 as u32), 2),
+            (Self::F32, _) => words(u64::from((v as f32).to_bits()), 2),
+            (Self::F64, _) => words(v.to_bits(), 4),
+            (Self::U64, Value::UInt(v)) => words(*v, 4),
+            (Self::U64, _) => words(v.round() as u64, 4),
+            (Self::I64, Value::Int(v)) => words(*v as u64, 4),
+            (Self::I64, _) => words(v.round() as i64 as u64, 4),
+            (Self::String { len, .. }, _) => {
+                let mut bytes = match value {
+                    Value::String(s) => s.as_bytes().to_vec(),
+                    _ => Vec::new(),
+                };
+                bytes.resize(2 * usize::from(len), 0);
+                bytes
+                    .chunks(2)
+                    .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+                    .collect()
+            }
+        }
+    }
+}
+
+fn parse_u64(data: &[u16]) -> u64 {
+    parse_raw(&data[..4])
+}
+
+/// Big endian registers as unsigned integer, the lowest 64 bits are kept.
+fn parse_raw(data: &[u16]) -> u64 {
+    data.iter()
+        .fold(0, |acc, word| acc << 16 | u64::from(*word))
+}
+
+/// A decoded register value
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Float(f64),
+    Int(i64),
+    UInt(u64),
+    String(String),
+    Bool(bool),
+}
+
+impl Value {
+    /// Calculates `value * scaling + offset`.
+    /// Integers stay integers when not scaled.
+    fn scale(self, scaling: f64, offset: f64) -> Self {
+        match self {
+            Value::Float(v) => Value::Float(v * scaling + offset),
+            Value::Int(_) | Value::UInt(_) if scaling == 1.0 && offset == 0.0 => self,
+            Value::Int(v) => Value::Float(v as f64 * scaling + offset),
+            Value::UInt(v) => Value::Float(v as f64 * scaling + offset),
+            Value::String(_) | Value::Bool(_) => self,
+        }
+    }
+}
+
+/// Formats the value as InfluxDB line protocol field value
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Float(v) => write!(f, "{}", v),
+            Value::Int(v) => write!(f, "{}i", v),
+            Value::UInt(v) => write!(f, "{}u", v),
+            Value::Bool(v) => write!(f, "{}", v),
+            Value::String(v) => write!(f, "\"{}\"", v.replace('\\', "\\\\").replace('"', "\\\"")),
+        }
+    }
+}
+
+/// Order of the bytes of a value, from the first transmitted byte to the last.
+/// "A" is the most significant byte.
+/// For values larger than 32bit the pattern is extended to all registers.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ByteOrder {
+    /// Big endian
+    Abcd,
+    /// Little endian word order, big endian bytes in each word
+    Cdab,
+    /// Big endian word order, little endian bytes in each word
+    Badc,
+    /// Little endian
+    Dcba,
+}
+
+impl FromStr for ByteOrder {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ABCD" => Ok(Self::Abcd),
+            "CDAB" => Ok(Self::Cdab),
+            "BADC" => Ok(Self::Badc),
+            "DCBA" => Ok(Self::Dcba),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for ByteOrder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Self::Abcd => "ABCD",
+            Self::Cdab => "CDAB",
+            Self::Badc => "BADC",
+            Self::Dcba => "DCBA",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl ByteOrder {
+    /// Byte order to use for values of `data_type`.
+    /// The characters of a string are in transmission order, so only the bytes in each word are
+    /// swapped for strings, never the words.
+    pub fn for_data_type(self, data_type: DataType) -> Self {
+        match (self, data_type) {
+            (Self::Cdab, DataType::String { .. }) => Self::Abcd,
+            (Self::Dcba, DataType::String { .. }) => Self::Badc,
+            (byte_order, _) => byte_order,
+        }
+    }
+
+    /// Converts the registers to big endian (ABCD) order.
+    pub fn to_big_endian(self, data: &[u16]) -> Vec<u16> {
+        let swap_words = self == Self::Cdab || self == Self::Dcba;
+        let swap_bytes = self == Self::Badc || self == Self::Dcba;
+
+        let mut data = data.to_vec();
+        if swap_words {
+            data.reverse();
+        }
+        if swap_bytes {
+            data.iter_mut().for_each(|w| *w = w.swap_bytes());
+        }
+        data
+    }
+}
+
+#[derive(Copy, Clone, Debug, Display, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RegisterType {
+    #[display(fmt = "coil")]
+    Coil,
+    #[display(fmt = "discrete_input")]
+    DiscreteInput,
+    #[display(fmt = "input_register")]
+    InputRegister,
+    #[display(fmt = "holding_register")]
+    HoldingRegister,
+}
+
+impl FromStr for RegisterType {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "coil" => Ok(Self::Coil),
+            "discrete_input" => Ok(Self::DiscreteInput),
+            "input_register" => Ok(Self::InputRegister),
+            "holding_register" => Ok(Self::HoldingRegister),
+            _ => Err(()),
+        }
+    }
+}
+
+impl RegisterType {
+    /// Coils and discrete inputs are single bits, a data type does not make sense for them
+    pub fn is_bit(self) -> bool {
+        matches!(self, Self::Coil | Self::DiscreteInput)
+    }
+
+    /// Maximum number of registers (or bits) in one request according to the specification
+    pub fn max_request_len(self) -> u16 {
+        match self {
+            Self::Coil | Self::DiscreteInput => 2000,
+            Self::InputRegister | Self::HoldingRegister => 125,
+        }
+    }
+
+    /// Reads `len` consecutive registers of this type.
+    /// Coils and discrete inputs are returned as one word per bit (0 or 1).
+    pub fn read(self, mb: &mut dyn Client, start: u16, len: u16) -> Result<Vec<u16>, Error> {
+        let bits_to_words = |bits: Vec<Coil>| bits.into_iter().map(|b| (b == Coil::On) as u16);
+        match self {
+            Self::Coil => Ok(bits_to_words(mb.read_coils(start, len)?).collect()),
+            Self::DiscreteInput => {
+                Ok(bits_to_words(mb.read_discrete_inputs(start, len)?).collect())
+            }
+            Self::InputRegister => mb.read_input_registers(start, len),
+            Self::HoldingRegister => mb.read_holding_registers(start, len),
         }
     }
 }
 
 #[derive(Debug, PartialEq)]
 pub struct Device {
+    pub connection: String,
     pub id: u8,
     pub scan_interval: Duration,
     tags: BTreeMap<String, String>,
-    input_registers: Registers,
+    registers: BTreeMap<RegisterType, Registers>,
 }
 
 impl Device {
+    /// Fails if a register does not fit into one request or exceeds the address range.
     pub fn new(
+        connection: String,
         id: u8,
         scan_interval: Duration,
         tags: BTreeMap<String, String>,
-        input_registers: BTreeMap<u16, Register>,
-    ) -> Self {
-        Self {
+        registers: BTreeMap<RegisterType, BTreeMap<u16, Register>>,
+        request_options: RequestOptions,
+    ) -> Result<Self, String> {
+        let mut requests = BTreeMap::new();
+        for (reg_type, map) in registers {
+            if map.is_empty() {
+                continue;
+            }
+
+            let max_len = cmp::min(
+                reg_type.max_request_len(),
+                request_options.max_len.unwrap_or(u16::MAX),
+            );
+            for (addr, reg) in &map {
+                let len = reg.data_type.num_registers();
+                // A register is never split across requests
+                if len > max_len {
+                    return Err(format!(
+                        "{} `{}` is longer than the maximum request length {}",
+                        reg_type, reg.name, max_len
+                    ));
+                }
+                // Requests store the exclusive end address as `u16`
+                if u32::from(*addr) + u32::from(len) > u32::from(u16::MAX) {
+                    return Err(format!(
+                        "{} `{}` exceeds the address range",
+                        reg_type, reg.name
+                    ));
+                }
+            }
+
+            let registers = Registers::new(
+                map,
+                max_len,
+                request_options.max_gap,
+                &request_options.forbidden,
+            );
+            requests.insert(reg_type, registers);
+        }
+
+        Ok(Self {
+            connection,
             id,
             scan_interval,
             tags,
-            input_registers: Registers::new(input_registers),
+            registers: requests,
+        })
+    }
+
+    /// All registers with their type and address
+    pub fn registers(&self) -> impl Iterator<Item = (RegisterType, u16, &Register)> {
+        self.registers.iter().flat_map(|(reg_type, registers)| {
+            registers
+                .map
+                .iter()
+                .map(move |(addr, reg)| (*reg_type, *addr, reg))
+        })
+    }
+
+    /// Names of the outputs the registers are explicitly routed to
+    pub fn outputs(&self) -> impl Iterator<Item = &str> {
+        self.registers
+            .values()
+            .flat_map(|registers| registers.map.values())
+            .flat_map(|reg| reg.outputs.iter().flatten())
+            .map(String::as_str)
+    }
+
+    pub fn read(&self, mb: &mut dyn Client) -> Result<Reading, Error> {
+        let mut samples = Vec::new();
+        for (reg_type, registers) in &self.registers {
+            samples.append(&mut self.read_registers(mb, *reg_type, registers)?);
+        }
+
+        // Registers marked `as_tag` are attached to all other samples
+        let mut tags: Vec<(String, String)> = self.tags.clone().into_iter().collect();
+        for (reg, sample) in &samples {
+            match &sample.value {
+                Value::String(v) if reg.as_tag && !v.is_empty() => {
+                    tags.push((sample.name.clone(), v.clone()))
+                }
+                _ => {}
+            }
         }
+
+        Ok(Reading {
+            device_id: self.id,
+            tags,
+            samples: samples
+                .into_iter()
+                .filter(|(reg, _)| !reg.as_tag)
+                .map(|(_, sample)| sample)
+                .collect(),
+        })
     }
 
-    pub fn read(&self, mb: &mut impl Client) -> Result<String, Error> {
-        let mut influx_lines = String::new();
+    fn read_registers<'a>(
+        &self,
+        mb: &mut dyn Client,
+        reg_type: RegisterType,
+        registers: &'a Registers,
+    ) -> Result<Vec<(&'a Register, Sample)>, Error> {
+        let mut samples = Vec::new();
 
-        let register_map = &self.input_registers.map;
-        for req in &self.input_registers.requests {
+        let register_map = &registers.map;
+        for req in &registers.requests {
             mb.set_uid(self.id);
-            let resp = mb.read_input_registers(req.start, req.len())?;
+            let resp = reg_type.read(mb, req.start, req.len())?;
 
-            let id_string = self.id.to_string();
             let timestamp = SystemTime::now()
                 .duration_since(UNIX_EPOCH)
                 .unwrap()
@@ -100,36 +464,178 @@ impl Device {
 
             for (addr, reg) in register_map.range(req.start..req.end) {
                 let start_idx = (addr - req.start) as usize;
-                let data = &resp[start_idx..];
+                let end_idx = start_idx + reg.data_type.num_registers() as usize;
+                let data = reg
+                    .byte_order
+                    .for_data_type(reg.data_type)
+                    .to_big_endian(&resp[start_idx..end_idx]);
 
-                let value = reg.data_type.parse_data(data) * reg.scaling;
-                let tag_iter = self
-                    .tags
-                    .iter()
-                    .chain(&reg.tags)
-                    .map(|(k, v)| (k.as_str(), v.as_str()))
-                    .chain(iter::once(("modbus_id", id_string.as_str())));
-                influx_lines.push_str(&influxdb_line(&reg.name, tag_iter, value, timestamp));
+                let value = reg
+                    .data_type
+                    .parse_value(&data)
+                    .scale(reg.scaling, reg.offset);
+                samples.push((
+                    reg,
+                    Sample {
+                        name: reg.name.clone(),
+                        tags: reg.tags.clone().into_iter().collect(),
+                        value,
+                        timestamp,
+                        outputs: reg.outputs.clone(),
+                    },
+                ));
+
+                // Each bit field is an additional measurement
+                let raw = parse_raw(&data);
+                for (name, bits) in &reg.bits {
+                    samples.push((
+                        reg,
+                        Sample {
+                            name: name.clone(),
+                            tags: reg.tags.clone().into_iter().collect(),
+                            value: bits.extract(raw),
+                            timestamp,
+                            outputs: reg.outputs.clone(),
+                        },
+                    ));
+                }
+            }
+        }
+
+        Ok(samples)
+    }
+}
+
+/// The merged configuration with the requests sent on each scan
+impl fmt::Display for Device {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Device with id `{}` on connection `{}`, scan interval {}",
+            self.id,
+            self.connection,
+            humantime::format_duration(self.scan_interval)
+        )?;
+        for (k, v) in &self.tags {
+            writeln!(f, "  Tag {}={}", k, v)?;
+        }
+        for (reg_type, registers) in &self.registers {
+            for req in &registers.requests {
+                writeln!(
+                    f,
+                    "  Read {} {}..={} (length {})",
+                    reg_type,
+                    req.start,
+                    req.end - 1,
+                    req.len()
+                )?;
+                for (addr, reg) in registers.map.range(req.start..req.end) {
+                    writeln!(f, "    {} {}", addr, reg)?;
+                }
             }
         }
+        Ok(())
+    }
+}
+
+/// All values from one read of a device
+#[derive(Clone, Debug, PartialEq)]
+pub struct Reading {
+    pub device_id: u8,
+    /// Device tags and the values of registers marked `as_tag`
+    pub tags: Vec<(String, String)>,
+    pub samples: Vec<Sample>,
+}
+
+impl Reading {
+    /// The samples which are sent to `output`
+    pub fn for_output(&self, output: &str) -> Cow<'_, Reading> {
+        if self.samples.iter().all(|s| s.is_routed_to(output)) {
+            return Cow::Borrowed(self);
+        }
+
+        Cow::Owned(Reading {
+            device_id: self.device_id,
+            tags: self.tags.clone(),
+            samples: self
+                .samples
+                .iter()
+                .filter(|s| s.is_routed_to(output))
+                .cloned()
+                .collect(),
+        })
+    }
+}
+
+/// A decoded value of a register or bit field
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sample {
+    pub name: String,
+    /// Register tags
+    pub tags: Vec<(String, String)>,
+    pub value: Value,
+    /// Nanoseconds since the unix epoch
+    pub timestamp: u128,
+    /// Names of the outputs to send the value to, all outputs if `None`
+    pub outputs: Option<Vec<String>>,
+}
 
-        Ok(influx_lines)
+impl Sample {
+    fn is_routed_to(&self, output: &str) -> bool {
+        match &self.outputs {
+            Some(outputs) => outputs.iter().any(|o| o == output),
+            None => true,
+        }
     }
 }
 
-fn influxdb_line<'a, I>(measurement: &str, tags: I, value: f64, timestamp: u128) -> String
-where
-    I: Iterator<Item = (&'a str, &'a str)>,
-{
-    let escape_meas = |s: &str| s.replace(',', "\\,").replace(' ', "\\ ");
-    let escape_tag = |s: &str| escape_meas(s).replace('=', "\\=");
+/// Range of bits inside a register, 0 being the least significant bit
+#[derive(Clone, Debug, PartialEq)]
+pub struct Bits {
+    start: u8,
+    end: u8, // Inclusive
+}
+
+impl Bits {
+    /// `None` if `start` is after `end` or `end` is beyond the 64 bits of the largest register
+    pub fn new(start: u8, end: u8) -> Option<Self> {
+        if start <= end && end < 64 {
+            Some(Self { start, end })
+        } else {
+            None
+        }
+    }
+
+    pub fn start(&self) -> u8 {
+        self.start
+    }
 
-    let mut line = escape_meas(measurement);
-    for (k, v) in tags {
-        line.push_str(&format!(",{}={}", escape_tag(k), escape_tag(v)));
+    /// Inclusive
+    pub fn end(&self) -> u8 {
+        self.end
     }
-    line.push_str(&format!(" value={} {}\n", value, timestamp));
-    line
+
+    /// A single bit is a boolean, a range of bits an integer.
+    fn extract(&self, raw: u64) -> Value {
+        let len = u32::from(self.end - self.start + 1);
+        let bits = (raw >> self.start) & (u64::MAX >> (64 - len));
+        if len == 1 {
+            Value::Bool(bits == 1)
+        } else {
+            Value::Int(bits as i64)
+        }
+    }
+}
+
+/// Rules for combining registers into requests
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RequestOptions {
+    /// Maximum number of registers in one request, limited to the protocol maximum
+    pub max_len: Option<u16>,
+    /// Maximum number of unused registers read to combine two requests
+    pub max_gap: u16,
+    /// Addresses which are never read to fill a gap
+    pub forbidden: Vec<RangeInclusive<u16>>,
 }
 
 #[derive(Debug, PartialEq)]
@@ -140,15 +646,30 @@ struct Registers {
 }
 
 impl Registers {
-    fn new(map: BTreeMap<u16, Register>) -> Self {
+    /// Combines the registers into as few requests as possible.
+    /// A request does not include more than `max_len` registers.
+    /// Gaps of up to `max_gap` unused registers are read when they do not
+    /// contain any `forbidden` address.
+    fn new(
+        map: BTreeMap<u16, Register>,
+        max_len: u16,
+        max_gap: u16,
+        forbidden: &[RangeInclusive<u16>],
+    ) -> Self {
         let mut requests: Vec<Request> = Vec::new();
 
         // Registers are sorted by address
         for reg in &map {
             let curr = Request::new(*reg.0, reg.1.data_type.num_registers());
             match requests.last_mut() {
-                // Append consecutive registers to the current request
-                Some(ref mut prev) if curr.start <= prev.end => {
+                // Append consecutive (or nearby) registers to the current request
+                Some(ref mut prev)
+                    if curr.start <= prev.end.saturating_add(max_gap)
+                        && cmp::max(curr.end, prev.end) - prev.start <= max_len
+                        && !forbidden
+                            .iter()
+                            .any(|f| *f.start() < curr.start && *f.end() >= prev.end) =>
+                {
                     if curr.end > prev.end {
                         prev.end = curr.end;
                     }
@@ -163,13 +684,99 @@ impl Registers {
     }
 }
 
+/// Requests to read every address of `addresses`, split like the registers of a device.
+/// The last address (65535) cannot be read.
+pub fn address_requests(
+    reg_type: RegisterType,
+    addresses: RangeInclusive<u16>,
+    max_len: Option<u16>,
+) -> Vec<Range<u16>> {
+    let map = addresses
+        .map(|addr| {
+            let reg = Register::new(format!("{}_{}", reg_type, addr), DataType::U16);
+            (addr, reg)
+        })
+        .collect();
+    let max_len = cmp::min(reg_type.max_request_len(), max_len.unwrap_or(u16::MAX));
+    Registers::new(map, max_len, 0, &[])
+        .requests
+        .iter()
+        .map(|req| req.start..req.end)
+        .collect()
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct Register {
     pub data_type: DataType,
+    pub byte_order: ByteOrder,
     pub scaling: f64,
+    pub offset: f64,
 
     pub name: String,
     pub tags: BTreeMap<String, String>,
+    /// Attach the value as tag to all other measurements of the device
+    pub as_tag: bool,
+    /// Named bit fields stored as additional measurements
+    pub bits: BTreeMap<String, Bits>,
+    /// Names of the outputs to send the values to, all outputs if `None`
+    pub outputs: Option<Vec<String>>,
+}
+
+impl Register {
+    /// Unscaled register in big endian byte order without tags, bits or outputs
+    pub fn new(name: impl Into<String>, data_type: DataType) -> Self {
+        Register {
+            data_type,
+            byte_order: ByteOrder::Abcd,
+            scaling: 1.0,
+            offset: 0.0,
+            name: name.into(),
+            tags: BTreeMap::new(),
+            as_tag: false,
+            bits: BTreeMap::new(),
+            outputs: None,
+        }
+    }
+
+    /// Raw register contents for a value, inverse of reading it
+    pub fn to_data(&self, value: &Value) -> Vec<u16> {
+        let raw = match value {
+            Value::String(_) => value.clone(),
+            Value::Bool(v) => Value::Bool(*v),
+            _ if self.scaling == 1.0 && self.offset == 0.0 => value.clone(),
+            Value::Float(v) => Value::Float((v - self.offset) / self.scaling),
+            Value::Int(v) => Value::Float((*v as f64 - self.offset) / self.scaling),
+            Value::UInt(v) => Value::Float((*v as f64 - self.offset) / self.scaling),
+        };
+        self.byte_order
+            .for_data_type(self.data_type)
+            .to_big_endian(&self.data_type.to_data(&raw))
+    }
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "`{}`: {}", self.name, self.data_type)?;
+        if self.byte_order != ByteOrder::Abcd {
+            write!(f, ", byte order {}", self.byte_order)?;
+        }
+        if self.scaling != 1.0 || self.offset != 0.0 {
+            write!(f, ", scaling {}, offset {}", self.scaling, self.offset)?;
+        }
+        if self.as_tag {
+            write!(f, ", as tag")?;
+        }
+        for (name, bits) in &self.bits {
+            write!(f, ", bits {}={}..={}", name, bits.start, bits.end)?;
+        }
+        for (k, v) in &self.tags {
+            write!(f, ", tag {}={}", k, v)?;
+        }
+        if let Some(outputs) = &self.outputs {
+            write!(f, ", outputs {}", outputs.join(", "))?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -201,24 +808,14 @@ mod tests {
         registers.insert(
             1,
             Register {
-                name: String::from("foobar"),
-                tags: BTreeMap::new(),
-                data_type: DataType::F32,
                 scaling: 8.7,
+                ..Register::new("foobar", DataType::F32)
             },
         );
-        registers.insert(
-            3,
-            Register {
-                name: String::from("quxbaz"),
-                tags: BTreeMap::new(),
-                data_type: DataType::U16,
-                scaling: 1.0,
-            },
-        );
+        registers.insert(3, Register::new("quxbaz", DataType::U16));
 
         let requests = vec![Request::new(1, 3)];
-        assert_eq!(requests, Registers::new(registers).requests);
+        assert_eq!(requests, Registers::new(registers, 125, 0, &[]).requests);
     }
 
     #[test]
@@ -227,24 +824,14 @@ mod tests {
         registers.insert(
             1,
             Register {
-                name: String::from("foobar"),
-                tags: BTreeMap::new(),
-                data_type: DataType::F32,
                 scaling: 8.7,
+                ..Register::new("foobar", DataType::F32)
             },
         );
-        registers.insert(
-            8,
-            Register {
-                name: String::from("quxbaz"),
-                tags: BTreeMap::new(),
-                data_type: DataType::U16,
-                scaling: 1.0,
-            },
-        );
+        registers.insert(8, Register::new("quxbaz", DataType::U16));
 
         let requests = vec![Request::new(1, 2), Request::new(8, 1)];
-        assert_eq!(requests, Registers::new(registers).requests);
+        assert_eq!(requests, Registers::new(registers, 125, 0, &[]).requests);
     }
 
     #[test]
@@ -253,24 +840,172 @@ mod tests {
         registers.insert(
             1,
             Register {
-                name: String::from("foobar"),
-                tags: BTreeMap::new(),
-                data_type: DataType::F64,
                 scaling: 8.7,
+                ..Register::new("foobar", DataType::F64)
             },
         );
-        registers.insert(
+        registers.insert(3, Register::new("quxbaz", DataType::U16));
+
+        let requests = vec![Request::new(1, 4)];
+        assert_eq!(requests, Registers::new(registers, 125, 0, &[]).requests);
+    }
+
+    #[test]
+    fn test_requests_from_registers_max_len() {
+        let mut registers = BTreeMap::new();
+        for addr in 0..10 {
+            registers.insert(
+                addr * 2,
+                Register::new(format!("foobar{}", addr), DataType::F32),
+            );
+        }
+
+        let requests = vec![Request::new(0, 8), Request::new(8, 8), Request::new(16, 4)];
+        assert_eq!(requests, Registers::new(registers, 9, 0, &[]).requests);
+    }
+
+    #[test]
+    fn test_requests_from_registers_max_gap() {
+        let mut registers = BTreeMap::new();
+        for addr in &[0, 12, 20, 40] {
+            registers.insert(
+                *addr,
+                Register::new(format!("foobar{}", addr), DataType::F32),
+            );
+        }
+
+        let requests = vec![Request::new(0, 22), Request::new(40, 2)];
+        assert_eq!(
+            requests,
+            Registers::new(registers.clone(), 125, 10, &[]).requests
+        );
+
+        // The gap between 2 and 12 must not be read
+        let requests = vec![
+            Request::new(0, 2),
+            Request::new(12, 10),
+            Request::new(40, 2),
+        ];
+        assert_eq!(
+            requests,
+            Registers::new(registers, 125, 10, &[5..=5]).requests
+        );
+    }
+
+    #[test]
+    fn test_reading_for_output() {
+        let sample = |name: &str, outputs: Option<Vec<String>>| Sample {
+            name: String::from(name),
+            tags: Vec::new(),
+            value: Value::Int(1),
+            timestamp: 0,
+            outputs,
+        };
+        let reading = Reading {
+            device_id: 1,
+            tags: Vec::new(),
+            samples: vec![
+                sample("voltage", None),
+                sample("alarm", Some(vec![String::from("alarms")])),
+            ],
+        };
+
+        let names = |output| -> Vec<_> {
+            reading
+                .for_output(output)
+                .samples
+                .iter()
+                .map(|s| s.name.clone())
+                .collect()
+        };
+        assert_eq!(names("alarms"), vec!["voltage", "alarm"]);
+        assert_eq!(names("local"), vec!["voltage"]);
+        assert!(matches!(reading.for_output("alarms"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn test_device_new_errors() {
+        let device = |addr, data_type, max_len| {
+            let mut map = BTreeMap::new();
+            map.insert(addr, Register::new("power", data_type));
+            let mut registers = BTreeMap::new();
+            registers.insert(RegisterType::InputRegister, map);
+            let request_options = RequestOptions {
+                max_len,
+                ..RequestOptions::default()
+            };
+            Device::new(
+                String::from("gw1"),
+                1,
+                Duration::from_secs(1),
+                BTreeMap::new(),
+                registers,
+                request_options,
+            )
+        };
+
+        assert!(device(0xFFFB, DataType::U32, None).is_ok());
+        assert_eq!(
+            device(0xFFFE, DataType::U32, None).unwrap_err(),
+            "input_register `power` exceeds the address range"
+        );
+        assert_eq!(
+            device(0, DataType::U64, Some(2)).unwrap_err(),
+            "input_register `power` is longer than the maximum request length 2"
+        );
+        assert_eq!(
+            device(
+                0,
+                DataType::String {
+                    len: 126,
+                    trim: true
+                },
+                None
+            )
+            .unwrap_err(),
+            "input_register `power` is longer than the maximum request length 125"
+        );
+    }
+
+    #[test]
+    fn test_device_display() {
+        let mut input_registers = BTreeMap::new();
+        input_registers.insert(1, Register::new("power", DataType::F32));
+        input_registers.insert(
             3,
             Register {
-                name: String::from("quxbaz"),
-                tags: BTreeMap::new(),
-                data_type: DataType::U16,
-                scaling: 1.0,
+                byte_order: ByteOrder::Cdab,
+                scaling: 0.1,
+                outputs: Some(vec![String::from("local")]),
+                ..Register::new("energy", DataType::U32)
             },
         );
+        input_registers.insert(200, Register::new("status", DataType::U16));
+        let mut registers = BTreeMap::new();
+        registers.insert(RegisterType::InputRegister, input_registers);
 
-        let requests = vec![Request::new(1, 4)];
-        assert_eq!(requests, Registers::new(registers).requests);
+        let mut tags = BTreeMap::new();
+        tags.insert(String::from("site"), String::from("north"));
+
+        let device = Device::new(
+            String::from("gw1"),
+            5,
+            Duration::from_secs(90),
+            tags,
+            registers,
+            RequestOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            device.to_string(),
+            "Device with id `5` on connection `gw1`, scan interval 1m 30s\n\
+             \x20 Tag site=north\n\
+             \x20 Read input_register 1..=4 (length 4)\n\
+             \x20   1 `power`: f32\n\
+             \x20   3 `energy`: u32, byte order CDAB, scaling 0.1, offset 0, outputs local\n\
+             \x20 Read input_register 200..=200 (length 1)\n\
+             \x20   200 `status`: u16\n"
+        );
     }
 
     #[test]
@@ -289,4 +1024,193 @@ mod tests {
         let dt = DataType::I32;
         assert_eq!(dt.parse_data(&data[..]), 0x2468ACF0i32 as f64);
     }
+
+    #[test]
+    fn test_register_parse_value_64bit() {
+        let data: [u16; 4] = [0xFFFF, 0xFFFF, 0xFFFF, 0xFFFE];
+
+        let dt = DataType::U64;
+        assert_eq!(
+            dt.parse_value(&data[..]),
+            Value::UInt(0xFFFF_FFFF_FFFF_FFFE)
+        );
+
+        let dt = DataType::I64;
+        assert_eq!(dt.parse_value(&data[..]), Value::Int(-2));
+
+        // Exact above 2^53
+        let data: [u16; 4] = [0x0020, 0x0000, 0x0000, 0x0001];
+        let dt = DataType::U64;
+        assert_eq!(dt.parse_value(&data[..]), Value::UInt((1 << 53) + 1));
+    }
+
+    #[test]
+    fn test_register_parse_value_string() {
+        let data: [u16; 4] = [0x4142, 0x4344, 0x2000, 0x0000];
+
+        let dt = DataType::String { len: 4, trim: true };
+        assert_eq!(
+            dt.parse_value(&data[..]),
+            Value::String(String::from("ABCD"))
+        );
+
+        let dt = DataType::String {
+            len: 3,
+            trim: false,
+        };
+        assert_eq!(
+            dt.parse_value(&data[..]),
+            Value::String(String::from("ABCD \0"))
+        );
+
+        let data = ByteOrder::Badc.to_big_endian(&data[..2]);
+        let dt = DataType::String { len: 2, trim: true };
+        assert_eq!(dt.parse_value(&data), Value::String(String::from("BADC")));
+    }
+
+    #[test]
+    fn test_data_type_to_data() {
+        let values = vec![
+            (DataType::U16, Value::Float(1234.0)),
+            (DataType::I16, Value::Float(-2.0)),
+            (DataType::U32, Value::Float(70000.0)),
+            (DataType::I32, Value::Float(-70000.0)),
+            (DataType::F32, Value::Float(1.5)),
+            (DataType::F64, Value::Float(-0.1)),
+            (DataType::U64, Value::UInt(u64::MAX)),
+            (DataType::I64, Value::Int(i64::MIN)),
+            (
+                DataType::String { len: 3, trim: true },
+                Value::String(String::from("ABC")),
+            ),
+        ];
+        for (data_type, value) in values {
+            let data = data_type.to_data(&value);
+            assert_eq!(data.len(), usize::from(data_type.num_registers()));
+            assert_eq!(data_type.parse_value(&data), value);
+        }
+
+        assert_eq!(DataType::U16.to_data(&Value::Float(-1.0)), vec![0]);
+        assert_eq!(DataType::I16.to_data(&Value::Float(1.6)), vec![2]);
+
+        let reg = Register {
+            byte_order: ByteOrder::Cdab,
+            scaling: 0.1,
+            offset: 10.0,
+            ..Register::new("power", DataType::I32)
+        };
+        assert_eq!(reg.to_data(&Value::Float(-10.0)), vec![0xFF38, 0xFFFF]);
+
+        // Word order does not apply to strings
+        let reg = Register {
+            byte_order: ByteOrder::Cdab,
+            ..Register::new("serial", DataType::String { len: 4, trim: true })
+        };
+        let value = Value::String(String::from("ABCDEFGH"));
+        assert_eq!(reg.to_data(&value), vec![0x4142, 0x4344, 0x4546, 0x4748]);
+    }
+
+    #[test]
+    fn test_bits_extract() {
+        let raw = parse_raw(&[0x0001, 0x80F1]);
+
+        let bits = Bits::new(0, 0).unwrap();
+        assert_eq!(bits.extract(raw), Value::Bool(true));
+        let bits = Bits::new(1, 1).unwrap();
+        assert_eq!(bits.extract(raw), Value::Bool(false));
+        let bits = Bits::new(4, 7).unwrap();
+        assert_eq!(bits.extract(raw), Value::Int(0xF));
+        let bits = Bits::new(15, 20).unwrap();
+        assert_eq!(bits.extract(raw), Value::Int(0x3));
+        let bits = Bits::new(0, 63).unwrap();
+        assert_eq!(bits.extract(u64::MAX), Value::Int(-1));
+    }
+
+    #[test]
+    fn test_bits_new() {
+        assert_eq!(Bits::new(4, 4), Some(Bits { start: 4, end: 4 }));
+        assert_eq!(Bits::new(5, 4), None);
+        assert_eq!(Bits::new(0, 64), None);
+    }
+
+    #[test]
+    fn test_value_scale_and_format() {
+        assert_eq!(Value::UInt(12).scale(1.0, 0.0).to_string(), "12u");
+        assert_eq!(Value::Int(-12).scale(1.0, 0.0).to_string(), "-12i");
+        assert_eq!(Value::Int(-12).scale(0.5, 0.0).to_string(), "-6");
+        assert_eq!(Value::UInt(12).scale(1.0, 0.5).to_string(), "12.5");
+        assert_eq!(Value::Float(1.5).scale(2.0, -4.0).to_string(), "-1");
+        assert_eq!(Value::Bool(true).scale(2.0, 1.0).to_string(), "true");
+        assert_eq!(
+            Value::String(String::from(r#"a"b\c"#))
+                .scale(2.0, 1.0)
+                .to_string(),
+            r#""a\"b\\c""#
+        );
+    }
+
+    #[test]
+    fn test_byte_order_to_big_endian() {
+        let data: [u16; 2] = [0x0102, 0x0304];
+        assert_eq!(ByteOrder::Abcd.to_big_endian(&data), vec![0x0102, 0x0304]);
+        assert_eq!(ByteOrder::Cdab.to_big_endian(&data), vec![0x0304, 0x0102]);
+        assert_eq!(ByteOrder::Badc.to_big_endian(&data), vec![0x0201, 0x0403]);
+        assert_eq!(ByteOrder::Dcba.to_big_endian(&data), vec![0x0403, 0x0201]);
+
+        let dt = DataType::String { len: 2, trim: true };
+        let abcd = ByteOrder::Abcd.for_data_type(dt);
+        assert_eq!(abcd.to_big_endian(&data), vec![0x0102, 0x0304]);
+        let cdab = ByteOrder::Cdab.for_data_type(dt);
+        assert_eq!(cdab.to_big_endian(&data), vec![0x0102, 0x0304]);
+        let badc = ByteOrder::Badc.for_data_type(dt);
+        assert_eq!(badc.to_big_endian(&data), vec![0x0201, 0x0403]);
+        let dcba = ByteOrder::Dcba.for_data_type(dt);
+        assert_eq!(dcba.to_big_endian(&data), vec![0x0201, 0x0403]);
+    }
+
+    #[test]
+    fn test_register_parse_data_byte_order() {
+        // 123.456 as f32 is 0x42F6E979
+        let abcd: [u16; 2] = [0x42F6, 0xE979];
+        let cdab: [u16; 2] = [0xE979, 0x42F6];
+        let badc: [u16; 2] = [0xF642, 0x79E9];
+        let dcba: [u16; 2] = [0x79E9, 0xF642];
+
+        let dt = DataType::F32;
+        let expected = f64::from(123.456f32);
+        assert_eq!(
+            dt.parse_data(&ByteOrder::Abcd.to_big_endian(&abcd)),
+            expected
+        );
+        assert_eq!(
+            dt.parse_data(&ByteOrder::Cdab.to_big_endian(&cdab)),
+            expected
+        );
+        assert_eq!(
+            dt.parse_data(&ByteOrder::Badc.to_big_endian(&badc)),
+            expected
+        );
+        assert_eq!(
+            dt.parse_data(&ByteOrder::Dcba.to_big_endian(&dcba)),
+            expected
+        );
+
+        // 0x0102030405060708 as f64
+        let expected = f64::from_bits(0x0102_0304_0506_0708);
+        let dt = DataType::F64;
+        let cdab: [u16; 4] = [0x0708, 0x0506, 0x0304, 0x0102];
+        let dcba: [u16; 4] = [0x0807, 0x0605, 0x0403, 0x0201];
+        assert_eq!(
+            dt.parse_data(&ByteOrder::Cdab.to_big_endian(&cdab)),
+            expected
+        );
+        assert_eq!(
+            dt.parse_data(&ByteOrder::Dcba.to_big_endian(&dcba)),
+            expected
+        );
+
+        let dt = DataType::I32;
+        let cdab: [u16; 2] = [0xFFFE, 0xFFFF];
+        assert_eq!(dt.parse_data(&ByteOrder::Cdab.to_big_endian(&cdab)), -2.0);
+    }
 }
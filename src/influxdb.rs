@@ -0,0 +1,410 @@
+use std::io::{self, Write};
+use std::iter;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::buffer::DiskBuffer;
+use crate::config::InfluxDbConfig;
+use crate::device::{Reading, Value};
+use crate::output::Output;
+use crate::queue::{Overflow, Queue};
+use derive_more::Display;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use isahc::http::header::CONTENT_ENCODING;
+use isahc::http::{HeaderValue, StatusCode};
+use isahc::{self, Error as HttpError};
+use log::{debug, error, info, warn};
+
+#[derive(Debug, Display)]
+pub enum Error {
+    Http(HttpError),
+    Other(String),
+    /// InfluxDB refused the data, sending it again would fail again
+    Rejected(String),
+    #[display(fmt = "Buffer: {}", "_0")]
+    Buffer(io::Error),
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Buffer(e)
+    }
+}
+
+/// Writes lines to InfluxDB.
+///
+/// Lines from multiple readings are combined into one request.
+/// Lines that cannot be written are kept in the disk buffer (when configured)
+/// and are sent in their original order as soon as InfluxDB is reachable again.
+pub struct InfluxDb {
+    name: String,
+    config: InfluxDbConfig,
+    queue: Queue<String>,
+    overflow: Overflow,
+    flush_interval: Duration,
+    buffer: Option<Mutex<DiskBuffer>>,
+}
+
+impl InfluxDb {
+    /// Time to wait before sending again after a failed write
+    const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+    pub fn new(name: &str, config: InfluxDbConfig) -> io::Result<Self> {
+        let buffer = match &config.buffer {
+            Some(buffer_config) => {
+                let buffer = DiskBuffer::open(&buffer_config.path, buffer_config.max_size())
+                    .map_err(|e| {
+                        io::Error::new(
+                            e.kind(),
+                            format!("`{}`: Cannot open buffer: {}", buffer_config.path, e),
+                        )
+                    })?;
+                if !buffer.is_empty() {
+                    info!("{}: {} batches left in buffer", name, buffer.len());
+                }
+                Some(Mutex::new(buffer))
+            }
+            None => None,
+        };
+
+        let overflow = config.overflow()?;
+        if overflow == Overflow::Spill && buffer.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Overflow policy `spill` requires a buffer",
+            ));
+        }
+
+        Ok(Self {
+            name: String::from(name),
+            queue: Queue::new(config.queue_size()),
+            overflow,
+            flush_interval: config.flush_interval()?,
+            buffer,
+            config,
+        })
+    }
+
+    /// Collects queued lines until the batch size is reached or the flush interval elapsed.
+    /// Lines that do not fit into the batch anymore are stored in `next`.
+    fn next_batch(&self, next: &mut Option<String>) -> Option<String> {
+        let mut batch = next.take().or_else(|| self.queue.pop())?;
+        let mut batch_len = batch.lines().count();
+
+        let deadline = Instant::now() + self.flush_interval;
+        while batch_len < self.config.batch_size() {
+            let lines = match self.queue.pop_until(deadline) {
+                Some(lines) => lines,
+                None => break,
+            };
+            let len = lines.lines().count();
+            if batch_len + len > self.config.batch_size() {
+                *next = Some(lines);
+                break;
+            }
+            batch.push_str(&lines);
+            batch_len += len;
+        }
+
+        Some(batch)
+    }
+
+    fn buffer_lines(&self, lines: &str) {
+        let mut buffer = self.buffer.as_ref().unwrap().lock().unwrap();
+        if let Err(e) = buffer.push(lines) {
+            error!("{}: Buffer: {}", self.name, e);
+        }
+    }
+
+    /// Moves the queued lines into the buffer, starting with `next`.
+    fn spill_queue(&self, next: &mut Option<String>) {
+        let now = Instant::now();
+        let queued = iter::from_fn(|| self.queue.pop_until(now));
+        for lines in next.take().into_iter().chain(queued) {
+            self.buffer_lines(&lines);
+        }
+    }
+
+    fn write(&self, lines: String, next: &mut Option<String>) -> Result<(), Error> {
+        let buffer = match &self.buffer {
+            Some(buffer) => buffer,
+            None => return self.send(lines),
+        };
+
+        if buffer.lock().unwrap().is_empty() {
+            let result = self.send(lines.clone());
+            if matches!(result, Err(Error::Http(_)) | Err(Error::Other(_))) {
+                buffer.lock().unwrap().push(&lines)?;
+            }
+            return result;
+        }
+
+        // Queue behind the buffered lines to keep the order
+        buffer.lock().unwrap().push(&lines)?;
+        debug!(
+            "{}: Replaying {} buffered batches",
+            self.name,
+            buffer.lock().unwrap().len()
+        );
+
+        // Do not hold the lock while sending, pollers might have to spill data
+        loop {
+            // The queue would overflow while replaying a large buffer
+            self.spill_queue(next);
+            let front = buffer.lock().unwrap().front(self.config.batch_size())?;
+            let (seq, batch) = match front {
+                Some(front) => front,
+                None => break,
+            };
+            match self.send(batch) {
+                Ok(()) => {}
+                Err(Error::Rejected(e)) => warn!("{}: Dropping buffered lines: {}", self.name, e),
+                Err(e) => return Err(e),
+            }
+            buffer.lock().unwrap().pop(seq)?;
+        }
+        info!("{}: Buffer replayed", self.name);
+        Ok(())
+    }
+
+    fn send(&self, lines: String) -> Result<(), Error> {
+        let req = if self.config.gzip() {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            let body = encoder
+                .write_all(lines.as_bytes())
+                .and_then(|_| encoder.finish())
+                .expect("Failed to compress InfluxDB http request");
+            let mut req = self.config.to_request(body);
+            req.headers_mut()
+                .insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+            req
+        } else {
+            self.config.to_request(lines.into_bytes())
+        };
+        match isahc::send(req) {
+            Ok(resp)
+                if resp.status() == StatusCode::BAD_REQUEST
+                    || resp.status() == StatusCode::UNPROCESSABLE_ENTITY =>
+            {
+                Err(Error::Rejected(format!("{:?}", resp)))
+            }
+            Ok(resp) if !resp.status().is_success() => Err(Error::Other(format!("{:?}", resp))),
+            Err(e) => Err(Error::Http(e)),
+            Ok(_) => Ok(()),
+        }
+    }
+}
+
+impl Output for InfluxDb {
+    /// Only blocks when the queue is full and the `block` overflow policy is configured.
+    fn push(&self, reading: &Reading) {
+        let lines = to_lines(reading);
+        match self.overflow {
+            Overflow::Block => self.queue.push_wait(lines),
+            Overflow::DropOldest => {
+                if self.queue.push(lines).is_some() {
+                    warn!("{}: Queue full, dropping oldest data", self.name);
+                }
+            }
+            Overflow::Spill => {
+                if let Some(oldest) = self.queue.push(lines) {
+                    self.buffer_lines(&oldest);
+                }
+            }
+        }
+    }
+
+    fn run(&self) {
+        let mut retry_at = Instant::now();
+        let mut dropped = 0;
+        let mut next = None;
+
+        while let Some(lines) = self.next_batch(&mut next) {
+            let now = Instant::now();
+            if now < retry_at {
+                if self.buffer.is_some() {
+                    // No need to try, InfluxDB was not reachable a moment ago
+                    self.buffer_lines(&lines);
+                    continue;
+                }
+                if self.queue.is_closed() {
+                    dropped += 1;
+                    continue;
+                }
+                // Keep the data in the queue meanwhile
+                thread::sleep(retry_at - now);
+            }
+
+            if let Err(e) = self.write(lines, &mut next) {
+                warn!("{}: {}", self.name, e);
+                retry_at = Instant::now() + Self::RETRY_DELAY;
+            }
+        }
+
+        if dropped > 0 {
+            warn!("{}: Dropped {} batches on exit", self.name, dropped);
+        }
+    }
+
+    fn close(&self) {
+        self.queue.close();
+    }
+}
+
+/// InfluxDB line protocol with one line for each sample.
+/// Samples with NaN or infinite values are skipped, InfluxDB would reject the whole request.
+pub fn to_lines(reading: &Reading) -> String {
+    let id = reading.device_id.to_string();
+    let mut lines = String::new();
+    for sample in &reading.samples {
+        if let Value::Float(f) = sample.value {
+            if !f.is_finite() {
+                warn!(
+                    "Device {}: Skipping `{}` with value {}",
+                    reading.device_id, sample.name, f
+                );
+                continue;
+            }
+        }
+        let tags = reading
+            .tags
+            .iter()
+            .chain(&sample.tags)
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .chain(iter::once(("modbus_id", id.as_str())));
+        lines.push_str(&line(&sample.name, tags, &sample.value, sample.timestamp));
+    }
+    lines
+}
+
+fn line<'a, I>(measurement: &str, tags: I, value: &Value, timestamp: u128) -> String
+where
+    I: Iterator<Item = (&'a str, &'a str)>,
+{
+    let escape_meas = |s: &str| s.replace(',', "\\,").replace(' ', "\\ ");
+    let escape_tag = |s: &str| escape_meas(s).replace('=', "\\=");
+
+    let mut line = escape_meas(measurement);
+    for (k, v) in tags {
+        line.push_str(&format!(",{}={}", escape_tag(k), escape_tag(v)));
+    }
+    line.push_str(&format!(" value={} {}\n", value, timestamp));
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::device::Sample;
+
+    fn reading(names: &[&str]) -> Reading {
+        Reading {
+            device_id: 1,
+            tags: Vec::new(),
+            samples: names
+                .iter()
+                .map(|name| Sample {
+                    name: name.to_string(),
+                    tags: Vec::new(),
+                    value: Value::Int(1),
+                    timestamp: 1,
+                    outputs: None,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_to_lines() {
+        let reading = Reading {
+            device_id: 3,
+            tags: vec![(String::from("phase"), String::from("L 1"))],
+            samples: vec![
+                Sample {
+                    name: String::from("pressure"),
+                    tags: vec![(String::from("unit"), String::from("bar"))],
+                    value: Value::Float(1.5),
+                    timestamp: 1000,
+                    outputs: None,
+                },
+                Sample {
+                    name: String::from("alarm,high"),
+                    tags: Vec::new(),
+                    value: Value::Bool(true),
+                    timestamp: 1000,
+                    outputs: None,
+                },
+            ],
+        };
+        assert_eq!(
+            to_lines(&reading),
+            "pressure,phase=L\\ 1,unit=bar,modbus_id=3 value=1.5 1000\n\
+             alarm\\,high,phase=L\\ 1,modbus_id=3 value=true 1000\n"
+        );
+    }
+
+    #[test]
+    fn test_to_lines_non_finite() {
+        let mut reading = reading(&["a", "b", "c", "d"]);
+        reading.samples[0].value = Value::Float(f64::NAN);
+        reading.samples[1].value = Value::Float(0.5);
+        reading.samples[2].value = Value::Float(f64::INFINITY);
+        reading.samples[3].value = Value::Float(f64::NEG_INFINITY);
+        assert_eq!(to_lines(&reading), "b,modbus_id=1 value=0.5 1\n");
+    }
+
+    #[test]
+    fn test_next_batch() {
+        let config = toml::from_str(
+            r#"
+            hostname = "http://localhost:8086"
+            database = "testdb"
+            batch_size = 3
+            "#,
+        )
+        .unwrap();
+        let influxdb = InfluxDb::new("influxdb", config).unwrap();
+        influxdb.push(&reading(&["a", "b"]));
+        influxdb.push(&reading(&["c"]));
+        influxdb.push(&reading(&["d", "e"]));
+        influxdb.push(&reading(&["f", "g"]));
+        influxdb.close();
+
+        // Lines of one reading are never split
+        let mut next = None;
+        let batches: Vec<_> = std::iter::from_fn(|| influxdb.next_batch(&mut next)).collect();
+        assert_eq!(
+            batches,
+            vec![
+                "a,modbus_id=1 value=1i 1\nb,modbus_id=1 value=1i 1\nc,modbus_id=1 value=1i 1\n",
+                "d,modbus_id=1 value=1i 1\ne,modbus_id=1 value=1i 1\n",
+                "f,modbus_id=1 value=1i 1\ng,modbus_id=1 value=1i 1\n",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_buffer_error() {
+        // The buffer directory cannot be created inside a file
+        let file = std::env::temp_dir().join(format!("data-collector-file-{}", std::process::id()));
+        std::fs::write(&file, "").unwrap();
+        let path = file.join("buffer");
+        let config = toml::from_str(&format!(
+            r#"
+            hostname = "http://localhost:8086"
+            database = "testdb"
+
+            [buffer]
+            path = "{}"
+            "#,
+            path.display()
+        ))
+        .unwrap();
+        let e = InfluxDb::new("influxdb", config).err().unwrap();
+        assert!(e.to_string().contains("Cannot open buffer"));
+        std::fs::remove_file(&file).unwrap();
+    }
+}
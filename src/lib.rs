@@ -0,0 +1,53 @@
+//! Reads values from Modbus devices and writes them to InfluxDB, MQTT or Prometheus.
+//!
+//! The `data-collector` binary is a thin command line interface on top of this library.
+//! To embed the polling engine, parse a configuration into a [`config::Setup`], or build
+//! the [`device::Device`]s directly, and start a [`scheduler::Scheduler`] with the
+//! [`source::Source`]s to read from and the [`output::Output`]s to write to:
+//!
+//! ```no_run
+//! use std::sync::Arc;
+//!
+//! use data_collector::config::Config;
+//! use data_collector::output::{self, Output};
+//! use data_collector::scheduler::Scheduler;
+//! use data_collector::source::Source;
+//!
+//! let config: Config = toml::from_str(&std::fs::read_to_string("config.toml").unwrap()).unwrap();
+//! let setup = config.into_setup().unwrap();
+//!
+//! let sources: Vec<(String, Box<dyn Source>)> = setup
+//!     .connections
+//!     .into_iter()
+//!     .map(|(name, config)| (name, Box::new(config) as Box<dyn Source>))
+//!     .collect();
+//! let outputs: Vec<(String, Arc<dyn Output>)> = setup
+//!     .outputs
+//!     .into_iter()
+//!     .map(|(name, config)| {
+//!         let output = output::from_config(&name, config)?;
+//!         Ok((name, output))
+//!     })
+//!     .collect::<std::io::Result<_>>()
+//!     .unwrap();
+//!
+//! let scheduler = Scheduler::start(sources, setup.devices, outputs).unwrap();
+//! let shutdown = scheduler.shutdown_handle();
+//! // Call `shutdown.shutdown()` from another thread to stop
+//! scheduler.join();
+//! ```
+
+mod buffer;
+pub mod config;
+pub mod device;
+pub mod influxdb;
+pub mod mqtt;
+pub mod output;
+pub mod prometheus;
+pub mod queue;
+mod rtu;
+pub mod scheduler;
+pub mod source;
+
+/// The `Client` trait which sources return
+pub use modbus;
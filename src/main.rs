@@ -1,37 +1,23 @@
-mod config;
-mod device;
+mod probe;
+mod read;
+mod scan;
+mod simulate;
 
-use std::cell::RefCell;
-use std::convert::TryFrom;
 use std::fs::{self, File};
+use std::process;
+use std::sync::Arc;
 
-use crate::config::{Config, InfluxDbConfig};
-use crate::device::Device;
 use chrono::Local;
-use clap::{app_from_crate, crate_authors, crate_description, crate_name, crate_version, Arg};
-use ctrlc;
-use derive_more::{Display, From};
-use futures::{self, channel::mpsc, executor, prelude::*, select, stream};
-use futures_timer::Interval;
-use isahc::{self, Error as HttpError};
-use log::{debug, error, info, warn};
-use modbus::{tcp::Transport, Error as ModbusError};
+use clap::{
+    app_from_crate, crate_authors, crate_description, crate_name, crate_version, Arg, SubCommand,
+};
+use data_collector::config::{Config, OutputConfig, Setup};
+use data_collector::output::{self, Output};
+use data_collector::scheduler::Scheduler;
+use data_collector::source::Source;
+use log::info;
 use simplelog::{Config as LogConfig, TermLogger, TerminalMode, WriteLogger};
 
-#[derive(Debug, Display, From)]
-enum Error {
-    #[display(fmt = "ModbusTCP: {}", "_0")]
-    Modbus(ModbusError),
-    #[display(fmt = "InfluxDB: {}", "_0")]
-    InfluxDb(InfluxDbError),
-}
-
-#[derive(Debug, Display, From)]
-enum InfluxDbError {
-    Http(HttpError),
-    Other(String),
-}
-
 fn main() -> Result<(), Box<dyn std::error::Error + 'static>> {
     // Parse command line arguments
     let matches = app_from_crate!()
@@ -42,6 +28,7 @@ fn main() -> Result<(), Box<dyn std::error::Error + 'static>> {
                 .takes_value(true)
                 .value_name("FILE")
                 .default_value("config.toml")
+                .global(true)
                 .help("Sets a custom config file"),
         )
         .arg(
@@ -60,6 +47,195 @@ fn main() -> Result<(), Box<dyn std::error::Error + 'static>> {
                 .possible_values(&["off", "error", "warn", "info", "debug", "trace"])
                 .help("Sets the logging level"),
         )
+        .subcommand(
+            SubCommand::with_name("check")
+                .about("Checks the configuration file and prints the devices without connecting"),
+        )
+        .subcommand(
+            SubCommand::with_name("read")
+                .about("Reads registers once and prints the values")
+                .arg(
+                    Arg::with_name("id")
+                        .required(true)
+                        .value_name("ID")
+                        .help("Modbus unit id"),
+                )
+                .arg(
+                    Arg::with_name("connection")
+                        .long("connection")
+                        .takes_value(true)
+                        .value_name("NAME")
+                        .help("Sets the connection [default: modbus]"),
+                )
+                .arg(
+                    Arg::with_name("device")
+                        .long("device")
+                        .conflicts_with_all(&["type", "addr", "count", "data_type", "byte_order"])
+                        .help("Reads the configured device and prints InfluxDB line protocol"),
+                )
+                .arg(
+                    Arg::with_name("type")
+                        .long("type")
+                        .takes_value(true)
+                        .value_name("TYPE")
+                        .default_value("holding_register")
+                        .possible_values(&[
+                            "coil",
+                            "discrete_input",
+                            "input_register",
+                            "holding_register",
+                        ])
+                        .help("Sets the register type"),
+                )
+                .arg(
+                    Arg::with_name("addr")
+                        .long("addr")
+                        .takes_value(true)
+                        .value_name("ADDR")
+                        .default_value("0")
+                        .help("Sets the address of the first register"),
+                )
+                .arg(
+                    Arg::with_name("count")
+                        .long("count")
+                        .takes_value(true)
+                        .value_name("COUNT")
+                        .default_value("1")
+                        .help("Sets the number of values, or the number of registers for strings"),
+                )
+                .arg(
+                    Arg::with_name("data_type")
+                        .long("data-type")
+                        .takes_value(true)
+                        .value_name("TYPE")
+                        .default_value("u16")
+                        .possible_values(&[
+                            "u16", "u32", "u64", "i16", "i32", "i64", "f32", "f64", "string",
+                        ])
+                        .help("Sets the data type of the values"),
+                )
+                .arg(
+                    Arg::with_name("byte_order")
+                        .long("byte-order")
+                        .takes_value(true)
+                        .value_name("ORDER")
+                        .default_value("ABCD")
+                        .possible_values(&["ABCD", "CDAB", "BADC", "DCBA"])
+                        .help("Sets the byte order of the values"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("scan")
+                .about("Probes all unit ids and prints the ones which respond")
+                .arg(
+                    Arg::with_name("connection")
+                        .long("connection")
+                        .takes_value(true)
+                        .value_name("NAME")
+                        .help("Sets the connection [default: modbus]"),
+                )
+                .arg(
+                    Arg::with_name("type")
+                        .long("type")
+                        .takes_value(true)
+                        .value_name("TYPE")
+                        .default_value("holding_register")
+                        .possible_values(&[
+                            "coil",
+                            "discrete_input",
+                            "input_register",
+                            "holding_register",
+                        ])
+                        .help("Sets the type of the probed register"),
+                )
+                .arg(
+                    Arg::with_name("addr")
+                        .long("addr")
+                        .takes_value(true)
+                        .value_name("ADDR")
+                        .default_value("0")
+                        .help("Sets the address of the probed register"),
+                )
+                .arg(
+                    Arg::with_name("timeout")
+                        .long("timeout")
+                        .takes_value(true)
+                        .value_name("DURATION")
+                        .default_value("100ms")
+                        .help("Sets the time to wait for each unit id"),
+                )
+                .arg(
+                    Arg::with_name("skeleton")
+                        .long("skeleton")
+                        .takes_value(true)
+                        .value_name("FILE")
+                        .help("Writes a `[[devices]]` entry for each responding unit id"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("probe")
+                .about("Finds the input and holding register addresses a device serves")
+                .arg(
+                    Arg::with_name("id")
+                        .required(true)
+                        .value_name("ID")
+                        .help("Modbus unit id"),
+                )
+                .arg(
+                    Arg::with_name("connection")
+                        .long("connection")
+                        .takes_value(true)
+                        .value_name("NAME")
+                        .help("Sets the connection [default: modbus]"),
+                )
+                .arg(
+                    Arg::with_name("range")
+                        .long("range")
+                        .takes_value(true)
+                        .value_name("START..END")
+                        .default_value("0..9999")
+                        .help("Sets the probed addresses, including END"),
+                )
+                .arg(
+                    Arg::with_name("max_registers")
+                        .long("max-registers")
+                        .takes_value(true)
+                        .value_name("COUNT")
+                        .help("Sets the maximum number of registers per request"),
+                )
+                .arg(
+                    Arg::with_name("template")
+                        .long("template")
+                        .takes_value(true)
+                        .value_name("FILE")
+                        .help("Writes a device template with the addresses which returned data"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("simulate")
+                .about("Serves the configured devices as Modbus TCP slave with simulated values")
+                .arg(
+                    Arg::with_name("connection")
+                        .long("connection")
+                        .takes_value(true)
+                        .value_name("NAME")
+                        .help("Sets the connection of the simulated devices [default: modbus]"),
+                )
+                .arg(
+                    Arg::with_name("listen")
+                        .long("listen")
+                        .takes_value(true)
+                        .value_name("ADDR")
+                        .help("Sets the listen address [default: port of the connection]"),
+                )
+                .arg(
+                    Arg::with_name("values")
+                        .long("values")
+                        .takes_value(true)
+                        .value_name("FILE")
+                        .help("Sets the file with the value generators of the registers"),
+                ),
+        )
         .get_matches();
 
     // Setup logging
@@ -85,92 +261,83 @@ fn main() -> Result<(), Box<dyn std::error::Error + 'static>> {
     info!("Reading configuration file: {}", &config_file);
 
     let config_str = fs::read_to_string(config_file)?;
-    let config: Config = toml::from_str(&config_str)?;
-
-    let devices = config.devices.into_devices();
-
-    // Connect Modbus
-    let modbus_config = config.modbus;
-    let (modbus_hostname, modbus_config) = modbus_config.into_modbus_tcp_config();
-
-    debug!("Connecting to {}", modbus_hostname);
-    let mb = Transport::new_with_cfg(&modbus_hostname, modbus_config)?;
-    let mb = &RefCell::new(mb);
-
-    let influxdb_config = &config.influxdb;
+    let invalid = |e: String| -> ! {
+        eprintln!("Invalid configuration file `{}`:\n{}", config_file, e);
+        process::exit(1);
+    };
+    let setup = toml::from_str::<Config>(&config_str)
+        .map_err(|e| e.to_string())
+        .and_then(|config| config.into_setup().map_err(|e| e.to_string()))
+        .unwrap_or_else(|e| invalid(e));
 
-    // Share one failure counter for all devices.
-    // With each failed device communication the counter is increased.
-    // With each successfull device communicationt the counter is decreased.
-    // When the counter reaches the threshold (e.g. all devices on the bus failed
-    // two times in a row) action is taken.
-    let mut fail_count = 0;
-    let scan_interval_iter = devices.iter().map(|d| d.scan_interval.as_nanos());
-    let fail_count_threshold = 2
-        * devices.len()
-        * usize::try_from(
-            scan_interval_iter.clone().max().unwrap() / scan_interval_iter.clone().min().unwrap(),
-        )
-        .unwrap();
-    debug!("fail_count_threshold={}", fail_count_threshold);
+    let subcommand = match matches.subcommand() {
+        ("read", Some(matches)) => Some(read::run(&setup, matches)),
+        ("scan", Some(matches)) => Some(scan::run(&setup, matches)),
+        ("probe", Some(matches)) => Some(probe::run(&setup, matches)),
+        ("simulate", Some(matches)) => Some(simulate::run(&setup, matches)),
+        _ => None,
+    };
+    if let Some(result) = subcommand {
+        if let Err(e) = result {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+        return Ok(());
+    }
 
-    // A stream that yields a refence to a device every time its `scan_interval` is due.
-    let device_intervals = devices
-        .iter()
-        .map(|dev| Interval::new(dev.scan_interval).map(move |_| dev));
+    if let Err(e) = setup.check_collecting() {
+        invalid(e.to_string());
+    }
+    if matches.subcommand_matches("check").is_some() {
+        print_setup(&setup);
+        return Ok(());
+    }
 
-    // Combine all device interval streams into one to process one device after the other.
-    let mut device_results = stream::select_all(device_intervals)
-        .map(move |dev| process_device(dev, &mut mb.borrow_mut(), influxdb_config))
-        .inspect_ok(|dev| debug!("Device {} processed successfully", dev.id))
-        .inspect_err(|e| warn!("{}", e));
+    let mut outputs: Vec<(String, Arc<dyn Output>)> = Vec::new();
+    for (name, config) in setup.outputs {
+        match output::from_config(&name, config) {
+            Ok(output) => outputs.push((name, output)),
+            Err(e) => {
+                eprintln!("Output `{}`: {}", name, e);
+                process::exit(1);
+            }
+        }
+    }
+    let sources: Vec<(String, Box<dyn Source>)> = setup
+        .connections
+        .into_iter()
+        .map(|(name, config)| (name, Box::new(config) as Box<dyn Source>))
+        .collect();
+    let scheduler = Scheduler::start(sources, setup.devices, outputs)?;
 
     // Handling for graceful shutdown
-    let (shutdown_tx, mut shutdown_rx) = mpsc::channel(1);
-    ctrlc::set_handler(move || shutdown_tx.clone().try_send(()).unwrap()).unwrap();
+    let shutdown = scheduler.shutdown_handle();
+    ctrlc::set_handler(move || shutdown.shutdown()).unwrap();
 
-    executor::block_on(async move {
-        loop {
-            select! {
-                _ = shutdown_rx.next() => {
-                    info!("Graceful exit");
-                    break;
-                }
-                r = device_results.next() => {
-                    if r.unwrap().is_err() {
-                        fail_count += 1;
-                        debug!("fail_count={}", fail_count);
-                    } else if fail_count > 0 {
-                        fail_count -= 1;
-                        debug!("fail_count={}", fail_count);
-                    }
-
-                    if fail_count >= fail_count_threshold {
-                        error!("{} modbus communication errors, exiting...", fail_count);
-                        break;
-                    }
-                }
-            }
-        }
-    });
+    // Send the remaining data after the polling stopped
+    scheduler.join();
 
     Ok(())
 }
 
-fn process_device<'a>(
-    dev: &'a Device,
-    mb: &mut Transport,
-    influxdb_config: &InfluxDbConfig,
-) -> Result<&'a Device, Error> {
-    let lines = dev.read(mb)?;
+/// Prints the merged configuration and the requests sent to the devices.
+fn print_setup(setup: &Setup) {
+    println!("Connections:");
+    for name in setup.connections.keys() {
+        println!("  {}", name);
+    }
 
-    let req = influxdb_config.to_request(lines);
-    let resp = isahc::send(req);
-    match resp {
-        Ok(resp) if !resp.status().is_success() => {
-            Err(InfluxDbError::Other(format!("{:?}", resp)).into())
-        }
-        Err(e) => Err(InfluxDbError::Http(e).into()),
-        Ok(_) => Ok(dev),
+    println!("Outputs:");
+    for (name, output) in &setup.outputs {
+        let output_type = match output {
+            OutputConfig::InfluxDb(_) => "influxdb",
+            OutputConfig::Mqtt(_) => "mqtt",
+            OutputConfig::Prometheus(_) => "prometheus",
+        };
+        println!("  {} ({})", name, output_type);
+    }
+
+    for dev in &setup.devices {
+        print!("\n{}", dev);
     }
 }
@@ -0,0 +1,353 @@
+use std::fs;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use crate::config::{MqttConfig, MqttFormat};
+use crate::device::{Reading, Sample, Value};
+use crate::output::Output;
+use crate::queue::Queue;
+use log::{info, warn};
+use rumqttc::{
+    Client, ClientError, Connection, Event, Incoming, LastWill, MqttOptions, Outgoing, QoS,
+    Transport,
+};
+use serde_json::{json, Map, Value as JsonValue};
+
+/// Publishes the readings to a MQTT broker.
+pub struct Mqtt {
+    name: String,
+    config: MqttConfig,
+    format: MqttFormat,
+    qos: QoS,
+    client: Client,
+    /// Network event loop, moved to its own thread by `run()`
+    connection: Mutex<Option<Connection>>,
+    queue: Queue<Reading>,
+    closed: AtomicBool,
+}
+
+impl Mqtt {
+    /// Time to wait before connecting again
+    const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+    pub fn new(name: &str, config: MqttConfig) -> io::Result<Self> {
+        let format = config.format()?;
+        let qos = config.qos()?;
+        let mut options = MqttOptions::new(config.client_id(), &config.hostname, config.port());
+        options.set_keep_alive(Duration::from_secs(30));
+        if let (Some(username), Some(password)) = (&config.username, &config.password) {
+            options.set_credentials(username, password);
+        }
+        if let Some(status_topic) = &config.status_topic {
+            options.set_last_will(LastWill::new(
+                status_topic,
+                "offline",
+                QoS::AtLeastOnce,
+                true,
+            ));
+        }
+        if config.tls() {
+            options.set_transport(tls_transport(&config)?);
+        }
+
+        let (client, connection) = Client::new(options, 100);
+        Ok(Self {
+            name: String::from(name),
+            queue: Queue::new(config.queue_size()),
+            config,
+            format,
+            qos,
+            client,
+            connection: Mutex::new(Some(connection)),
+            closed: AtomicBool::new(false),
+        })
+    }
+
+    /// Drives the network connection, reconnects on errors.
+    fn poll_connection(&self, mut connection: Connection) {
+        for notification in connection.iter() {
+            match notification {
+                Ok(Event::Incoming(Incoming::ConnAck(_))) => {
+                    info!("{}: Connected", self.name);
+                    // Must not wait here, this thread processes the requests
+                    if let Some(status_topic) = &self.config.status_topic {
+                        let result =
+                            self.client
+                                .try_publish(status_topic, QoS::AtLeastOnce, true, "online");
+                        if let Err(e) = result {
+                            warn!("{}: `{}`: {}", self.name, status_topic, e);
+                        }
+                    }
+                }
+                Ok(Event::Outgoing(Outgoing::Disconnect)) => break,
+                Ok(_) => {}
+                Err(e) => {
+                    if self.closed.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    warn!("{}: {}", self.name, e);
+                    thread::sleep(Self::RECONNECT_DELAY);
+                }
+            }
+        }
+    }
+
+    /// Waits while the request channel to the connection is full.
+    /// Gives up when the output is closed, the broker might be unreachable.
+    fn publish(&self, topic: &str, payload: impl Into<Vec<u8>>, qos: QoS, retain: bool) {
+        let payload = payload.into();
+        loop {
+            match self.client.try_publish(topic, qos, retain, payload.clone()) {
+                Ok(()) => return,
+                Err(ClientError::TryRequest(_)) if !self.closed.load(Ordering::Relaxed) => {
+                    thread::sleep(Duration::from_millis(100));
+                }
+                Err(e) => {
+                    warn!("{}: `{}`: {}", self.name, topic, e);
+                    return;
+                }
+            }
+        }
+    }
+}
+
+impl Output for Mqtt {
+    fn push(&self, reading: &Reading) {
+        if self.queue.push(reading.clone()).is_some() {
+            warn!("{}: Queue full, dropping oldest data", self.name);
+        }
+    }
+
+    fn run(&self) {
+        let connection = self.connection.lock().unwrap().take().unwrap();
+        thread::scope(|s| {
+            s.spawn(|| self.poll_connection(connection));
+
+            let (qos, retain) = (self.qos, self.config.retain());
+            while let Some(reading) = self.queue.pop() {
+                match self.format {
+                    MqttFormat::Value => {
+                        for sample in &reading.samples {
+                            let topic = topic(&self.config.topic, &reading, Some(sample));
+                            self.publish(&topic, payload(&sample.value), qos, retain);
+                        }
+                    }
+                    MqttFormat::Json => {
+                        let topic = topic(&self.config.topic, &reading, None);
+                        self.publish(&topic, json_payload(&reading), qos, retain);
+                    }
+                }
+            }
+
+            // The last will is only sent on connection loss
+            if let Some(status_topic) = &self.config.status_topic {
+                self.publish(status_topic, "offline", QoS::AtLeastOnce, true);
+            }
+            if self.client.try_disconnect().is_err() {
+                warn!("{}: Cannot disconnect", self.name);
+            }
+        });
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        self.queue.close();
+    }
+}
+
+fn tls_transport(config: &MqttConfig) -> io::Result<Transport> {
+    let read = |path: &String| {
+        fs::read(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("`{}`: Cannot read file: {}", path, e)))
+    };
+    let invalid = |msg| Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+
+    let client_auth = match (&config.client_cert, &config.client_key) {
+        (Some(cert), Some(key)) => Some((read(cert)?, read(key)?)),
+        (None, None) => None,
+        _ => return invalid("`client_cert` and `client_key` must be set together"),
+    };
+
+    match &config.ca_file {
+        Some(ca_file) => Ok(Transport::tls(read(ca_file)?, client_auth, None)),
+        None if client_auth.is_some() => invalid("`client_cert` requires `ca_file`"),
+        // Trust the CA certificates of the system
+        None => Ok(Transport::tls_with_default_config()),
+    }
+}
+
+/// Replaces the placeholders `{id}`, `{name}` and `{<tag>}` in the topic template.
+/// Unknown placeholders are replaced with an empty string.
+fn topic(template: &str, reading: &Reading, sample: Option<&Sample>) -> String {
+    let lookup = |key: &str| -> String {
+        match key {
+            "id" => return reading.device_id.to_string(),
+            "name" => return sample.map(|s| s.name.clone()).unwrap_or_default(),
+            _ => {}
+        }
+        sample
+            .into_iter()
+            .flat_map(|s| &s.tags)
+            .chain(&reading.tags)
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.clone())
+            .unwrap_or_default()
+    };
+
+    let mut topic = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => break,
+        };
+        topic.push_str(&rest[..start]);
+        topic.push_str(&lookup(&rest[start + 1..end]));
+        rest = &rest[end + 1..];
+    }
+    topic.push_str(rest);
+    topic
+}
+
+/// Plain text representation of a single value
+fn payload(value: &Value) -> String {
+    match value {
+        Value::Float(v) => v.to_string(),
+        Value::Int(v) => v.to_string(),
+        Value::UInt(v) => v.to_string(),
+        Value::String(v) => v.clone(),
+        Value::Bool(v) => v.to_string(),
+    }
+}
+
+fn json_value(value: &Value) -> JsonValue {
+    match value {
+        Value::Float(v) => json!(v),
+        Value::Int(v) => json!(v),
+        Value::UInt(v) => json!(v),
+        Value::String(v) => json!(v),
+        Value::Bool(v) => json!(v),
+    }
+}
+
+/// All values of a reading as JSON object
+fn json_payload(reading: &Reading) -> String {
+    let tags: Map<String, JsonValue> = reading
+        .tags
+        .iter()
+        .map(|(k, v)| (k.clone(), json!(v)))
+        .collect();
+    let values: Map<String, JsonValue> = reading
+        .samples
+        .iter()
+        .map(|s| (s.name.clone(), json_value(&s.value)))
+        .collect();
+    // Milliseconds, nanoseconds exceed the precision of JSON numbers
+    let timestamp = reading
+        .samples
+        .first()
+        .map_or(0, |s| s.timestamp / 1_000_000);
+
+    json!({
+        "modbus_id": reading.device_id,
+        "timestamp": timestamp as u64,
+        "tags": tags,
+        "values": values,
+    })
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reading() -> Reading {
+        Reading {
+            device_id: 7,
+            tags: vec![(String::from("site"), String::from("north"))],
+            samples: vec![
+                Sample {
+                    name: String::from("pressure"),
+                    tags: vec![(String::from("unit"), String::from("bar"))],
+                    value: Value::Float(1.5),
+                    timestamp: 1_571_000_000_123_000_000,
+                    outputs: None,
+                },
+                Sample {
+                    name: String::from("alarm"),
+                    tags: Vec::new(),
+                    value: Value::Bool(true),
+                    timestamp: 1_571_000_000_123_000_000,
+                    outputs: None,
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_topic() {
+        let r = reading();
+        let sample = Some(&r.samples[0]);
+        assert_eq!(
+            topic("{site}/{id}/{name}/{unit}", &r, sample),
+            "north/7/pressure/bar"
+        );
+        assert_eq!(topic("sensors/{id}", &r, None), "sensors/7");
+        assert_eq!(topic("{missing}/{name}", &r, None), "/");
+        assert_eq!(topic("broken/{id", &r, None), "broken/{id");
+    }
+
+    #[test]
+    fn test_payload() {
+        assert_eq!(payload(&Value::Float(1.5)), "1.5");
+        assert_eq!(payload(&Value::Int(-3)), "-3");
+        assert_eq!(payload(&Value::String(String::from("abc"))), "abc");
+        assert_eq!(payload(&Value::Bool(false)), "false");
+    }
+
+    #[test]
+    fn test_json_payload() {
+        let json: JsonValue = serde_json::from_str(&json_payload(&reading())).unwrap();
+        assert_eq!(
+            json,
+            json!({
+                "modbus_id": 7,
+                "timestamp": 1_571_000_000_123u64,
+                "tags": { "site": "north" },
+                "values": { "pressure": 1.5, "alarm": true },
+            })
+        );
+    }
+
+    #[test]
+    fn test_tls_transport() {
+        let config: MqttConfig = toml::from_str(
+            r#"
+            hostname = "localhost"
+            topic = "sensors"
+            tls = true
+            "#,
+        )
+        .unwrap();
+        // Fails when no crypto provider is enabled
+        assert!(matches!(tls_transport(&config), Ok(Transport::Tls(_))));
+
+        let config: MqttConfig = toml::from_str(
+            r#"
+            hostname = "localhost"
+            topic = "sensors"
+            tls = true
+            ca_file = "/nonexistent/ca.pem"
+            "#,
+        )
+        .unwrap();
+        let e = tls_transport(&config).err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+        assert!(e
+            .to_string()
+            .starts_with("`/nonexistent/ca.pem`: Cannot read file"));
+    }
+}
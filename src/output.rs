@@ -0,0 +1,33 @@
+use std::io;
+use std::sync::Arc;
+
+use crate::config::OutputConfig;
+use crate::device::Reading;
+use crate::influxdb::InfluxDb;
+use crate::mqtt::Mqtt;
+use crate::prometheus::Prometheus;
+
+/// Destination for the values read from the devices.
+///
+/// The pollers `push()` readings into the output. Each output runs `run()` in
+/// its own thread to send them, so that a slow output does not delay the polling.
+pub trait Output: Send + Sync {
+    /// Queues a reading to be sent by `run()`.
+    fn push(&self, reading: &Reading);
+
+    /// Sends queued readings until `close()` is called and the queue is empty.
+    fn run(&self);
+
+    /// Stops `run()` after the remaining readings were processed.
+    fn close(&self);
+}
+
+/// Creates the output for a configuration.
+/// Fails when a resource of the output is not available, e.g. a file or the listen address.
+pub fn from_config(name: &str, config: OutputConfig) -> io::Result<Arc<dyn Output>> {
+    Ok(match config {
+        OutputConfig::InfluxDb(c) => Arc::new(InfluxDb::new(name, c)?),
+        OutputConfig::Mqtt(c) => Arc::new(Mqtt::new(name, c)?),
+        OutputConfig::Prometheus(c) => Arc::new(Prometheus::new(name, c)?),
+    })
+}
@@ -0,0 +1,211 @@
+use std::fmt::{self, Write};
+use std::fs;
+use std::ops::{Range, RangeInclusive};
+
+use clap::{value_t, ArgMatches};
+use data_collector::config::{Setup, DEFAULT_CONNECTION};
+use data_collector::device::{self, RegisterType};
+use modbus::{Client, Error as ModbusError, ExceptionCode};
+
+/// Result of reading an address range
+#[derive(Debug, PartialEq)]
+enum Status {
+    Data,
+    Exception(ExceptionCode),
+    Invalid(String),
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Status::Data => write!(f, "Data"),
+            Status::Exception(code) => write!(f, "Exception {:?}", code),
+            Status::Invalid(e) => write!(f, "Invalid response: {}", e),
+        }
+    }
+}
+
+/// Status of consecutive address ranges
+type Results = Vec<(Range<u16>, Status)>;
+
+/// Finds the address ranges of the input and holding registers a device serves.
+pub fn run(setup: &Setup, matches: &ArgMatches) -> Result<(), String> {
+    let id = value_t!(matches, "id", u8).unwrap_or_else(|e| e.exit());
+    let name = matches.value_of("connection").unwrap_or(DEFAULT_CONNECTION);
+    let range = matches.value_of("range").unwrap();
+    let addresses = parse_range(range).ok_or_else(|| format!("Invalid range `{}`", range))?;
+    let max_len = matches
+        .value_of("max_registers")
+        .map(|_| value_t!(matches, "max_registers", u16).unwrap_or_else(|e| e.exit()));
+
+    let mut mb = setup
+        .connections
+        .get(name)
+        .ok_or_else(|| format!("Unknown connection `{}`", name))?
+        .connect()
+        .map_err(|e| format!("{}: {}", name, e))?;
+    mb.set_uid(id);
+
+    let mut probed = Vec::new();
+    for reg_type in [RegisterType::InputRegister, RegisterType::HoldingRegister] {
+        let mut results = Vec::new();
+        for req in device::address_requests(reg_type, addresses.clone(), max_len) {
+            bisect(mb.as_mut(), reg_type, req, &mut results)
+                .map_err(|e| format!("{}: Modbus: {}", name, e))?;
+        }
+
+        println!("{}", reg_type);
+        let results = merge(results);
+        for (range, status) in &results {
+            println!(
+                "  {:<14} {}",
+                format!("{}..={}", range.start, range.end - 1),
+                status
+            );
+        }
+        probed.push((reg_type, results));
+    }
+
+    if let Some(path) = matches.value_of("template") {
+        fs::write(path, template(id, &probed)).map_err(|e| format!("`{}`: {}", path, e))?;
+    }
+
+    Ok(())
+}
+
+/// Reads `range` and splits it in halves while the device rejects the addresses.
+/// Transport errors abort the probing, the device is not reachable.
+fn bisect(
+    mb: &mut dyn Client,
+    reg_type: RegisterType,
+    range: Range<u16>,
+    results: &mut Results,
+) -> Result<(), ModbusError> {
+    let status = match reg_type.read(mb, range.start, range.end - range.start) {
+        Ok(_) => Status::Data,
+        Err(ModbusError::Io(e)) => return Err(ModbusError::Io(e)),
+        Err(ModbusError::Exception(code)) => Status::Exception(code),
+        Err(e) => Status::Invalid(e.to_string()),
+    };
+
+    // Some devices report unserved addresses as illegal data value
+    let is_rejected = status == Status::Exception(ExceptionCode::IllegalDataAddress)
+        || status == Status::Exception(ExceptionCode::IllegalDataValue);
+    if is_rejected && range.len() > 1 {
+        let mid = range.start + (range.end - range.start) / 2;
+        bisect(mb, reg_type, range.start..mid, results)?;
+        bisect(mb, reg_type, mid..range.end, results)?;
+    } else {
+        results.push((range, status));
+    }
+    Ok(())
+}
+
+/// Combines adjacent ranges with the same status
+fn merge(results: Results) -> Results {
+    let mut merged: Results = Vec::new();
+    for (range, status) in results {
+        match merged.last_mut() {
+            Some((prev, prev_status)) if prev.end == range.start && *prev_status == status => {
+                prev.end = range.end;
+            }
+            _ => merged.push((range, status)),
+        }
+    }
+    merged
+}
+
+/// Device template with all addresses which returned data
+fn template(id: u8, probed: &[(RegisterType, Results)]) -> String {
+    let mut template = String::new();
+    writeln!(template, "[templates.unit_{}]", id).unwrap();
+    for (reg_type, results) in probed {
+        let addresses: Vec<_> = results
+            .iter()
+            .filter(|(_, status)| *status == Status::Data)
+            .flat_map(|(range, _)| range.clone())
+            .map(|addr| addr.to_string())
+            .collect();
+        if addresses.is_empty() {
+            continue;
+        }
+
+        writeln!(template, "{}s = [", reg_type).unwrap();
+        for line in addresses.chunks(10) {
+            writeln!(template, "    {},", line.join(", ")).unwrap();
+        }
+        writeln!(template, "]").unwrap();
+    }
+    template
+}
+
+/// Inclusive range like "0..9999", the last address (65535) is excluded
+fn parse_range(range: &str) -> Option<RangeInclusive<u16>> {
+    let mut parts = range.splitn(2, "..");
+    let start = parts.next()?.trim().parse().ok()?;
+    let end = parts.next()?.trim().parse().ok()?;
+    if start <= end && end < u16::MAX {
+        Some(start..=end)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge() {
+        let illegal = || Status::Exception(ExceptionCode::IllegalDataAddress);
+        let results = vec![
+            (0..62, Status::Data),
+            (62..125, Status::Data),
+            (125..130, illegal()),
+            (130..250, illegal()),
+            (250..251, Status::Data),
+        ];
+        assert_eq!(
+            merge(results),
+            vec![
+                (0..125, Status::Data),
+                (125..250, illegal()),
+                (250..251, Status::Data)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_template() {
+        let probed = vec![
+            (
+                RegisterType::InputRegister,
+                vec![
+                    (0..12, Status::Data),
+                    (12..20, Status::Exception(ExceptionCode::IllegalDataAddress)),
+                ],
+            ),
+            (
+                RegisterType::HoldingRegister,
+                vec![(0..20, Status::Exception(ExceptionCode::IllegalFunction))],
+            ),
+        ];
+        assert_eq!(
+            template(3, &probed),
+            "[templates.unit_3]\n\
+             input_registers = [\n\
+             \x20   0, 1, 2, 3, 4, 5, 6, 7, 8, 9,\n\
+             \x20   10, 11,\n\
+             ]\n"
+        );
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("0..9999"), Some(0..=9999));
+        assert_eq!(parse_range("100 .. 100"), Some(100..=100));
+        assert_eq!(parse_range("10..5"), None);
+        assert_eq!(parse_range("0..65535"), None);
+        assert_eq!(parse_range("100"), None);
+    }
+}
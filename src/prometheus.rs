@@ -0,0 +1,251 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::config::PrometheusConfig;
+use crate::device::{Reading, Value};
+use crate::output::Output;
+use log::{info, warn};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+/// Serves the most recent values as Prometheus metrics.
+pub struct Prometheus {
+    name: String,
+    prefix: String,
+    server: Server,
+    /// Values by metric name and labels
+    metrics: Mutex<BTreeMap<String, BTreeMap<String, f64>>>,
+    closed: AtomicBool,
+}
+
+impl Prometheus {
+    pub fn new(name: &str, config: PrometheusConfig) -> io::Result<Self> {
+        let server = Server::http(config.listen()).map_err(|e| {
+            io::Error::other(format!("`{}`: Cannot listen: {}", config.listen(), e))
+        })?;
+        info!("{}: Listening on {}", name, config.listen());
+
+        Ok(Self {
+            name: String::from(name),
+            prefix: config.prefix.unwrap_or_default(),
+            server,
+            metrics: Mutex::new(BTreeMap::new()),
+            closed: AtomicBool::new(false),
+        })
+    }
+
+    fn respond(&self, req: Request) {
+        let resp = if *req.method() == Method::Get && req.url() == "/metrics" {
+            let content_type =
+                Header::from_bytes("Content-Type", "text/plain; version=0.0.4").unwrap();
+            Response::from_string(self.render()).with_header(content_type)
+        } else {
+            Response::from_string("Not found, use /metrics").with_status_code(404)
+        };
+
+        if let Err(e) = req.respond(resp) {
+            warn!("{}: {}", self.name, e);
+        }
+    }
+
+    /// Text exposition format
+    fn render(&self) -> String {
+        let mut text = String::new();
+        for (name, series) in self.metrics.lock().unwrap().iter() {
+            writeln!(text, "# TYPE {} gauge", name).unwrap();
+            for (labels, value) in series {
+                writeln!(text, "{}{{{}}} {}", name, labels, format_value(*value)).unwrap();
+            }
+        }
+        text
+    }
+}
+
+impl Output for Prometheus {
+    fn push(&self, reading: &Reading) {
+        let id = reading.device_id.to_string();
+        let mut metrics = self.metrics.lock().unwrap();
+        for sample in &reading.samples {
+            let value = match &sample.value {
+                Value::Float(v) => *v,
+                Value::Int(v) => *v as f64,
+                Value::UInt(v) => *v as f64,
+                Value::Bool(v) => f64::from(u8::from(*v)),
+                // Not representable as metric
+                Value::String(_) => continue,
+            };
+
+            // Label names must be unique, register tags override device tags
+            // and the modbus id overrides both
+            let mut labels: BTreeMap<_, _> = reading
+                .tags
+                .iter()
+                .chain(&sample.tags)
+                .map(|(k, v)| (sanitize(k, false), v.as_str()))
+                .collect();
+            labels.insert(String::from("modbus_id"), &id);
+            let labels = labels
+                .iter()
+                .map(|(k, v)| format!("{}=\"{}\"", k, escape(v)))
+                .collect::<Vec<_>>()
+                .join(",");
+
+            let name = sanitize(&format!("{}{}", self.prefix, sample.name), true);
+            metrics.entry(name).or_default().insert(labels, value);
+        }
+    }
+
+    fn run(&self) {
+        while !self.closed.load(Ordering::Relaxed) {
+            match self.server.recv_timeout(Duration::from_millis(100)) {
+                Ok(Some(req)) => self.respond(req),
+                Ok(None) => {}
+                Err(e) => warn!("{}: {}", self.name, e),
+            }
+        }
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Replaces characters which are not allowed in metric (`allow_colon`) or label names.
+fn sanitize(name: &str, allow_colon: bool) -> String {
+    let mut sanitized: String = name
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' => c,
+            ':' if allow_colon => c,
+            _ => '_',
+        })
+        .collect();
+    if sanitized.starts_with(|c: char| c.is_ascii_digit()) || sanitized.is_empty() {
+        sanitized.insert(0, '_');
+    }
+    sanitized
+}
+
+fn format_value(value: f64) -> String {
+    match value {
+        v if v == f64::INFINITY => String::from("+Inf"),
+        v if v == f64::NEG_INFINITY => String::from("-Inf"),
+        v => v.to_string(), // NaN is written as `NaN`
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::device::Sample;
+
+    #[test]
+    fn test_sanitize() {
+        assert_eq!(sanitize("gas_density", true), "gas_density");
+        assert_eq!(sanitize("power (kW)", true), "power__kW_");
+        assert_eq!(sanitize("1st:value", true), "_1st:value");
+        assert_eq!(sanitize("1st:value", false), "_1st_value");
+    }
+
+    #[test]
+    fn test_render() {
+        let config = toml::from_str(r#"listen = "127.0.0.1:0""#).unwrap();
+        let prometheus = Prometheus::new("prometheus", config).unwrap();
+
+        let mut reading = Reading {
+            device_id: 1,
+            tags: vec![(String::from("phase"), String::from("L\"1\""))],
+            samples: vec![
+                Sample {
+                    name: String::from("temperature"),
+                    tags: vec![(String::from("unit"), String::from("°C"))],
+                    value: Value::Float(21.5),
+                    timestamp: 0,
+                    outputs: None,
+                },
+                Sample {
+                    name: String::from("alarm"),
+                    tags: Vec::new(),
+                    value: Value::Bool(true),
+                    timestamp: 0,
+                    outputs: None,
+                },
+                Sample {
+                    name: String::from("serial"),
+                    tags: Vec::new(),
+                    value: Value::String(String::from("ABC")),
+                    timestamp: 0,
+                    outputs: None,
+                },
+            ],
+        };
+        prometheus.push(&reading);
+
+        // Only the most recent value is kept
+        reading.samples[0].value = Value::Float(22.0);
+        prometheus.push(&reading);
+
+        assert_eq!(
+            prometheus.render(),
+            "# TYPE alarm gauge\n\
+             alarm{modbus_id=\"1\",phase=\"L\\\"1\\\"\"} 1\n\
+             # TYPE temperature gauge\n\
+             temperature{modbus_id=\"1\",phase=\"L\\\"1\\\"\",unit=\"°C\"} 22\n"
+        );
+    }
+
+    #[test]
+    fn test_duplicate_labels() {
+        let config = toml::from_str(r#"listen = "127.0.0.1:0""#).unwrap();
+        let prometheus = Prometheus::new("prometheus", config).unwrap();
+
+        let reading = Reading {
+            device_id: 1,
+            tags: vec![
+                (String::from("phase"), String::from("L1")),
+                (String::from("modbus_id"), String::from("meter")),
+            ],
+            samples: vec![Sample {
+                name: String::from("power"),
+                tags: vec![
+                    (String::from("phase"), String::from("L2")),
+                    // Same label name after sanitizing, the last one is kept
+                    (String::from("unit_"), String::from("W")),
+                    (String::from("unit!"), String::from("kW")),
+                ],
+                value: Value::Float(1.5),
+                timestamp: 0,
+                outputs: None,
+            }],
+        };
+        prometheus.push(&reading);
+
+        assert_eq!(
+            prometheus.render(),
+            "# TYPE power gauge\n\
+             power{modbus_id=\"1\",phase=\"L2\",unit_=\"kW\"} 1.5\n"
+        );
+    }
+
+    #[test]
+    fn test_listen_error() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let listen = listener.local_addr().unwrap();
+        let config = toml::from_str(&format!(r#"listen = "{}""#, listen)).unwrap();
+        let e = Prometheus::new("prometheus", config).err().unwrap();
+        assert!(e
+            .to_string()
+            .starts_with(&format!("`{}`: Cannot listen", listen)));
+    }
+}
@@ -0,0 +1,175 @@
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::sync::{Condvar, Mutex};
+use std::time::Instant;
+
+/// What to do when data is pushed into a full queue
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Overflow {
+    /// Drop the oldest data
+    DropOldest,
+    /// Wait until there is space in the queue
+    Block,
+    /// Move the oldest data to the disk buffer
+    Spill,
+}
+
+impl FromStr for Overflow {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop_oldest" => Ok(Self::DropOldest),
+            "block" => Ok(Self::Block),
+            "spill" => Ok(Self::Spill),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Bounded FIFO queue to pass data between threads.
+pub(crate) struct Queue<T> {
+    capacity: usize,
+    state: Mutex<State<T>>,
+    not_empty: Condvar,
+    not_full: Condvar,
+}
+
+struct State<T> {
+    items: VecDeque<T>,
+    closed: bool,
+}
+
+impl<T> Queue<T> {
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "Queue capacity must not be zero");
+        Self {
+            capacity,
+            state: Mutex::new(State {
+                items: VecDeque::with_capacity(capacity),
+                closed: false,
+            }),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+        }
+    }
+
+    /// Appends an item. Removes and returns the oldest item when the queue is full.
+    pub fn push(&self, item: T) -> Option<T> {
+        let mut state = self.state.lock().unwrap();
+        let oldest = if state.items.len() >= self.capacity {
+            state.items.pop_front()
+        } else {
+            None
+        };
+        state.items.push_back(item);
+        self.not_empty.notify_one();
+        oldest
+    }
+
+    /// Appends an item. Waits for space when the queue is full.
+    pub fn push_wait(&self, item: T) {
+        let mut state = self.state.lock().unwrap();
+        while state.items.len() >= self.capacity {
+            state = self.not_full.wait(state).unwrap();
+        }
+        state.items.push_back(item);
+        self.not_empty.notify_one();
+    }
+
+    /// Removes the oldest item. Waits for data when the queue is empty.
+    /// Returns `None` when the queue is closed and empty.
+    pub fn pop(&self) -> Option<T> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(item) = state.items.pop_front() {
+                self.not_full.notify_one();
+                return Some(item);
+            }
+            if state.closed {
+                return None;
+            }
+            state = self.not_empty.wait(state).unwrap();
+        }
+    }
+
+    /// Like `pop()` but gives up waiting for data at `deadline`.
+    pub fn pop_until(&self, deadline: Instant) -> Option<T> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(item) = state.items.pop_front() {
+                self.not_full.notify_one();
+                return Some(item);
+            }
+            let now = Instant::now();
+            if state.closed || now >= deadline {
+                return None;
+            }
+            state = self
+                .not_empty
+                .wait_timeout(state, deadline - now)
+                .unwrap()
+                .0;
+        }
+    }
+
+    /// Wakes up the consumer to process the remaining items and exit.
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.not_empty.notify_all();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state.lock().unwrap().closed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_queue_push_drops_oldest() {
+        let queue = Queue::new(2);
+        assert_eq!(queue.push(1), None);
+        assert_eq!(queue.push(2), None);
+        assert_eq!(queue.push(3), Some(1));
+        queue.close();
+        assert_eq!(queue.pop(), Some(2));
+        assert_eq!(queue.pop(), Some(3));
+        assert_eq!(queue.pop(), None);
+    }
+
+    #[test]
+    fn test_queue_pop_until() {
+        let queue = Queue::new(2);
+        queue.push(1);
+        let deadline = Instant::now() + Duration::from_millis(10);
+        assert_eq!(queue.pop_until(deadline), Some(1));
+        assert_eq!(queue.pop_until(deadline), None);
+        assert!(Instant::now() >= deadline);
+    }
+
+    #[test]
+    fn test_queue_push_wait() {
+        let queue = Arc::new(Queue::new(1));
+        let producer = {
+            let queue = queue.clone();
+            thread::spawn(move || {
+                for i in 0..100 {
+                    queue.push_wait(i);
+                }
+                queue.close();
+            })
+        };
+
+        // Nothing is lost or reordered
+        let received: Vec<_> = std::iter::from_fn(|| queue.pop()).collect();
+        assert_eq!(received, (0..100).collect::<Vec<_>>());
+        producer.join().unwrap();
+    }
+}
@@ -0,0 +1,189 @@
+use clap::{value_t, ArgMatches};
+use data_collector::config::{Setup, DEFAULT_CONNECTION};
+use data_collector::device::{ByteOrder, DataType, Device, RegisterType, Value};
+use data_collector::influxdb;
+use modbus::Client;
+
+/// Reads registers or a configured device once and prints the values.
+pub fn run(setup: &Setup, matches: &ArgMatches) -> Result<(), String> {
+    let id = value_t!(matches, "id", u8).unwrap_or_else(|e| e.exit());
+    let connection = matches.value_of("connection");
+
+    if matches.is_present("device") {
+        let dev = find_device(&setup.devices, id, connection)?;
+        let mut mb = connect(setup, &dev.connection)?;
+        let reading = dev
+            .read(mb.as_mut())
+            .map_err(|e| format!("{}: Modbus: {}", dev.connection, e))?;
+        print!("{}", influxdb::to_lines(&reading));
+        return Ok(());
+    }
+
+    let reg_type: RegisterType = matches.value_of("type").unwrap().parse().unwrap();
+    let addr = value_t!(matches, "addr", u16).unwrap_or_else(|e| e.exit());
+    let count = value_t!(matches, "count", u16).unwrap_or_else(|e| e.exit());
+    let byte_order: ByteOrder = matches.value_of("byte_order").unwrap().parse().unwrap();
+    let data_type = match matches.value_of("data_type").unwrap() {
+        // All registers as one string
+        "string" => DataType::String {
+            len: count,
+            trim: true,
+        },
+        t => t.parse().unwrap(),
+    };
+    let is_bit = reg_type.is_bit();
+    let (len, value_len) = request_len(reg_type, data_type, addr, count)?;
+
+    let connection = connection.unwrap_or(DEFAULT_CONNECTION);
+    let mut mb = connect(setup, connection)?;
+    mb.set_uid(id);
+    let data = reg_type
+        .read(mb.as_mut(), addr, len)
+        .map_err(|e| format!("{}: Modbus: {}", connection, e))?;
+
+    println!("{:<8} {:<20} Value", "Address", "Raw");
+    for (i, words) in data.chunks(value_len as usize).enumerate() {
+        let value = if is_bit {
+            Value::Bool(words[0] != 0)
+        } else {
+            let byte_order = byte_order.for_data_type(data_type);
+            data_type.parse_value(&byte_order.to_big_endian(words))
+        };
+        let raw: Vec<_> = words.iter().map(|w| format!("{:04x}", w)).collect();
+        println!(
+            "{:<8} {:<20} {}",
+            usize::from(addr) + i * usize::from(value_len),
+            raw.join(" "),
+            plain(&value)
+        );
+    }
+
+    Ok(())
+}
+
+/// Number of registers to read and number of registers per value.
+/// All values are read with one request.
+fn request_len(
+    reg_type: RegisterType,
+    data_type: DataType,
+    addr: u16,
+    count: u16,
+) -> Result<(u16, u16), String> {
+    if count == 0 {
+        return Err(String::from("`--count` must not be zero"));
+    }
+
+    let (num_values, value_len) = match data_type {
+        _ if reg_type.is_bit() => (count, 1),
+        DataType::String { len, .. } => (1, len),
+        _ => (count, data_type.num_registers()),
+    };
+    let len = u32::from(num_values) * u32::from(value_len);
+    let max_len = reg_type.max_request_len();
+    if len > u32::from(max_len) {
+        return Err(format!(
+            "Cannot read {} {}s with one request, the maximum is {}",
+            len, reg_type, max_len
+        ));
+    }
+    if u32::from(addr) + len > 0x10000 {
+        return Err(String::from("Addresses exceed the address range"));
+    }
+    Ok((len as u16, value_len))
+}
+
+/// The configured device, `connection` is required if the id is used on multiple connections
+fn find_device<'a>(
+    devices: &'a [Device],
+    id: u8,
+    connection: Option<&str>,
+) -> Result<&'a Device, String> {
+    let mut matching = devices
+        .iter()
+        .filter(|d| d.id == id && connection.unwrap_or(&d.connection) == d.connection);
+    match (matching.next(), matching.next()) {
+        (Some(dev), None) => Ok(dev),
+        (Some(_), Some(_)) => Err(format!(
+            "Multiple devices with id `{}` configured, select one with `--connection`",
+            id
+        )),
+        (None, _) => Err(format!("No device with id `{}` configured", id)),
+    }
+}
+
+fn connect(setup: &Setup, name: &str) -> Result<Box<dyn Client>, String> {
+    setup
+        .connections
+        .get(name)
+        .ok_or_else(|| format!("Unknown connection `{}`", name))?
+        .connect()
+        .map_err(|e| format!("{}: {}", name, e))
+}
+
+fn plain(value: &Value) -> String {
+    match value {
+        Value::Float(v) => v.to_string(),
+        Value::Int(v) => v.to_string(),
+        Value::UInt(v) => v.to_string(),
+        Value::String(v) => format!("{:?}", v),
+        Value::Bool(v) => v.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+
+    use data_collector::device::RequestOptions;
+
+    #[test]
+    fn test_request_len() {
+        let string = |len| DataType::String { len, trim: true };
+        let input = RegisterType::InputRegister;
+
+        assert_eq!(request_len(input, DataType::F32, 0, 10), Ok((20, 2)));
+        assert_eq!(request_len(input, string(8), 0, 8), Ok((8, 8)));
+        assert_eq!(
+            request_len(RegisterType::Coil, DataType::F32, 0, 2000),
+            Ok((2000, 1))
+        );
+
+        assert!(request_len(input, DataType::U16, 0, 0).is_err());
+        assert!(request_len(input, string(0), 0, 0).is_err());
+        assert_eq!(
+            request_len(input, DataType::F32, 0, 100),
+            Err(String::from(
+                "Cannot read 200 input_registers with one request, the maximum is 125"
+            ))
+        );
+        assert!(request_len(RegisterType::Coil, DataType::U16, 0, 2001).is_err());
+        assert!(request_len(input, DataType::U16, 0xFFFF, 2).is_err());
+    }
+
+    #[test]
+    fn test_find_device() {
+        let device = |connection: &str, id| {
+            Device::new(
+                String::from(connection),
+                id,
+                Duration::from_secs(1),
+                BTreeMap::new(),
+                BTreeMap::new(),
+                RequestOptions::default(),
+            )
+            .unwrap()
+        };
+        let devices = vec![device("gw1", 1), device("gw2", 1), device("gw2", 2)];
+
+        assert_eq!(find_device(&devices, 2, None).unwrap().connection, "gw2");
+        assert_eq!(
+            find_device(&devices, 1, Some("gw1")).unwrap().connection,
+            "gw1"
+        );
+        assert!(find_device(&devices, 1, None).is_err());
+        assert!(find_device(&devices, 2, Some("gw1")).is_err());
+    }
+}
@@ -0,0 +1,425 @@
+use std::io::{Read, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use modbus::{binary, Client, Coil, Error, ExceptionCode, Reason, Result};
+use serialport::{ClearBuffer, DataBits, Parity, SerialPort, StopBits};
+
+/// Config structure for the serial line settings
+#[derive(Clone, Copy)]
+pub struct Config {
+    pub baud_rate: u32,
+    pub parity: Parity,
+    pub data_bits: DataBits,
+    pub stop_bits: StopBits,
+    /// Time to wait for a response
+    pub timeout: Duration,
+    /// Silent interval between two frames. Defaults to 3.5 character times.
+    pub frame_delay: Option<Duration>,
+}
+
+impl Config {
+    fn default_frame_delay(&self) -> Duration {
+        // The specification recommends a fixed value for baud rates above 19200
+        if self.baud_rate > 19200 {
+            Duration::from_micros(1750)
+        } else {
+            // 3.5 characters with 11 bits each (start, 8 data, parity, stop)
+            Duration::from_micros(3_500_000 * 11 / u64::from(self.baud_rate))
+        }
+    }
+}
+
+/// ModbusRTU client on a serial line.
+pub struct Transport {
+    port: Box<dyn SerialPort>,
+    uid: u8,
+    frame_delay: Duration,
+    last_frame: Instant,
+}
+
+impl Transport {
+    pub fn new_with_cfg(path: &str, cfg: Config) -> Result<Transport> {
+        let port = serialport::new(path, cfg.baud_rate)
+            .parity(cfg.parity)
+            .data_bits(cfg.data_bits)
+            .stop_bits(cfg.stop_bits)
+            .timeout(cfg.timeout)
+            .open()
+            .map_err(|e| Error::Io(e.into()))?;
+
+        Ok(Transport {
+            port,
+            uid: 1,
+            frame_delay: cfg.frame_delay.unwrap_or_else(|| cfg.default_frame_delay()),
+            last_frame: Instant::now(),
+        })
+    }
+
+    /// Sends the request `pdu` and returns the response pdu.
+    /// `response_len` is the expected length of a successful response pdu.
+    fn transaction(&mut self, pdu: &[u8], response_len: usize) -> Result<Vec<u8>> {
+        let mut adu = Vec::with_capacity(pdu.len() + 3);
+        adu.push(self.uid);
+        adu.extend_from_slice(pdu);
+        adu.extend_from_slice(&crc16(&adu).to_le_bytes());
+
+        // Keep the bus silent between two frames
+        let elapsed = self.last_frame.elapsed();
+        if elapsed < self.frame_delay {
+            thread::sleep(self.frame_delay - elapsed);
+        }
+
+        // Discard garbage from previous (timed out) transactions
+        self.port
+            .clear(ClearBuffer::Input)
+            .map_err(|e| Error::Io(e.into()))?;
+        self.port.write_all(&adu)?;
+        self.port.flush()?;
+
+        let result = self.read_response(pdu[0], response_len);
+        self.last_frame = Instant::now();
+        result
+    }
+
+    fn read_response(&mut self, function: u8, response_len: usize) -> Result<Vec<u8>> {
+        // Unit id and function code
+        let mut adu = vec![0; 2];
+        self.port.read_exact(&mut adu)?;
+
+        let is_exception = adu[1] == function | 0x80;
+        let remaining = if is_exception { 1 } else { response_len - 1 };
+        adu.resize(2 + remaining + 2, 0);
+        self.port.read_exact(&mut adu[2..])?;
+
+        let (frame, crc) = adu.split_at(adu.len() - 2);
+        if crc16(frame).to_le_bytes() != crc {
+            return Err(Error::InvalidData(Reason::DecodingError));
+        }
+        if frame[0] != self.uid {
+            return Err(Error::InvalidResponse);
+        }
+        if is_exception {
+            return Err(exception_code(frame[2]).map_or(Error::InvalidResponse, Error::Exception));
+        }
+        if frame[1] != function {
+            return Err(Error::InvalidResponse);
+        }
+
+        Ok(frame[1..].to_vec())
+    }
+
+    fn read(&mut self, function: u8, addr: u16, count: u16, data_len: usize) -> Result<Vec<u8>> {
+        let mut pdu = vec![function];
+        pdu.extend_from_slice(&addr.to_be_bytes());
+        pdu.extend_from_slice(&count.to_be_bytes());
+
+        // Function code, byte count and data
+        let resp = self.transaction(&pdu, 2 + data_len)?;
+        if resp[1] as usize != data_len {
+            return Err(Error::InvalidData(Reason::UnexpectedReplySize));
+        }
+        Ok(resp[2..].to_vec())
+    }
+
+    fn write(&mut self, function: u8, addr: u16, value: u16, data: &[u8]) -> Result<()> {
+        let mut pdu = vec![function];
+        pdu.extend_from_slice(&addr.to_be_bytes());
+        pdu.extend_from_slice(&value.to_be_bytes());
+        if !data.is_empty() {
+            pdu.push(data.len() as u8);
+            pdu.extend_from_slice(data);
+        }
+
+        // Echo of function code, address and value/quantity
+        self.transaction(&pdu, 5).map(|_| ())
+    }
+}
+
+impl Client for Transport {
+    fn read_discrete_inputs(&mut self, addr: u16, count: u16) -> Result<Vec<Coil>> {
+        let bytes = self.read(0x02, addr, count, (count as usize).div_ceil(8))?;
+        Ok(binary::unpack_bits(&bytes, count))
+    }
+
+    fn read_coils(&mut self, addr: u16, count: u16) -> Result<Vec<Coil>> {
+        let bytes = self.read(0x01, addr, count, (count as usize).div_ceil(8))?;
+        Ok(binary::unpack_bits(&bytes, count))
+    }
+
+    fn write_single_coil(&mut self, addr: u16, value: Coil) -> Result<()> {
+        let value = if value == Coil::On { 0xff00 } else { 0x0000 };
+        self.write(0x05, addr, value, &[])
+    }
+
+    fn write_multiple_coils(&mut self, addr: u16, coils: &[Coil]) -> Result<()> {
+        let bytes = binary::pack_bits(coils);
+        self.write(0x0f, addr, coils.len() as u16, &bytes)
+    }
+
+    fn read_input_registers(&mut self, addr: u16, count: u16) -> Result<Vec<u16>> {
+        let bytes = self.read(0x04, addr, count, 2 * count as usize)?;
+        binary::pack_bytes(&bytes)
+    }
+
+    fn read_holding_registers(&mut self, addr: u16, count: u16) -> Result<Vec<u16>> {
+        let bytes = self.read(0x03, addr, count, 2 * count as usize)?;
+        binary::pack_bytes(&bytes)
+    }
+
+    fn write_single_register(&mut self, addr: u16, value: u16) -> Result<()> {
+        self.write(0x06, addr, value, &[])
+    }
+
+    fn write_multiple_registers(&mut self, addr: u16, values: &[u16]) -> Result<()> {
+        let bytes = binary::unpack_bytes(values);
+        self.write(0x10, addr, values.len() as u16, &bytes)
+    }
+
+    fn set_uid(&mut self, uid: u8) {
+        self.uid = uid;
+    }
+}
+
+fn exception_code(code: u8) -> Option<ExceptionCode> {
+    match code {
+        0x01 => Some(ExceptionCode::IllegalFunction),
+        0x02 => Some(ExceptionCode::IllegalDataAddress),
+        0x03 => Some(ExceptionCode::IllegalDataValue),
+        0x04 => Some(ExceptionCode::SlaveOrServerFailure),
+        0x05 => Some(ExceptionCode::Acknowledge),
+        0x06 => Some(ExceptionCode::SlaveOrServerBusy),
+        0x07 => Some(ExceptionCode::NegativeAcknowledge),
+        0x08 => Some(ExceptionCode::MemoryParity),
+        0x09 => Some(ExceptionCode::NotDefined),
+        0x0a => Some(ExceptionCode::GatewayPath),
+        0x0b => Some(ExceptionCode::GatewayTarget),
+        _ => None,
+    }
+}
+
+/// CRC-16/MODBUS checksum
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xFFFF;
+    for byte in data {
+        crc ^= u16::from(*byte);
+        for _ in 0..8 {
+            if crc & 1 != 0 {
+                crc = (crc >> 1) ^ 0xA001;
+            } else {
+                crc >>= 1;
+            }
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{self, Cursor};
+    use std::sync::{Arc, Mutex};
+
+    use serialport::{FlowControl, Result as SerialResult};
+
+    /// Serial port which answers with a fixed response and records the written bytes
+    struct FakePort {
+        written: Arc<Mutex<Vec<u8>>>,
+        response: Cursor<Vec<u8>>,
+    }
+
+    impl Read for FakePort {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.response.read(buf)? {
+                0 => Err(io::ErrorKind::TimedOut.into()),
+                n => Ok(n),
+            }
+        }
+    }
+
+    impl Write for FakePort {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SerialPort for FakePort {
+        fn name(&self) -> Option<String> {
+            None
+        }
+        fn baud_rate(&self) -> SerialResult<u32> {
+            Ok(9600)
+        }
+        fn data_bits(&self) -> SerialResult<DataBits> {
+            Ok(DataBits::Eight)
+        }
+        fn flow_control(&self) -> SerialResult<FlowControl> {
+            Ok(FlowControl::None)
+        }
+        fn parity(&self) -> SerialResult<Parity> {
+            Ok(Parity::Even)
+        }
+        fn stop_bits(&self) -> SerialResult<StopBits> {
+            Ok(StopBits::One)
+        }
+        fn timeout(&self) -> Duration {
+            Duration::from_secs(1)
+        }
+        fn set_baud_rate(&mut self, _: u32) -> SerialResult<()> {
+            Ok(())
+        }
+        fn set_data_bits(&mut self, _: DataBits) -> SerialResult<()> {
+            Ok(())
+        }
+        fn set_flow_control(&mut self, _: FlowControl) -> SerialResult<()> {
+            Ok(())
+        }
+        fn set_parity(&mut self, _: Parity) -> SerialResult<()> {
+            Ok(())
+        }
+        fn set_stop_bits(&mut self, _: StopBits) -> SerialResult<()> {
+            Ok(())
+        }
+        fn set_timeout(&mut self, _: Duration) -> SerialResult<()> {
+            Ok(())
+        }
+        fn write_request_to_send(&mut self, _: bool) -> SerialResult<()> {
+            Ok(())
+        }
+        fn write_data_terminal_ready(&mut self, _: bool) -> SerialResult<()> {
+            Ok(())
+        }
+        fn read_clear_to_send(&mut self) -> SerialResult<bool> {
+            Ok(true)
+        }
+        fn read_data_set_ready(&mut self) -> SerialResult<bool> {
+            Ok(true)
+        }
+        fn read_ring_indicator(&mut self) -> SerialResult<bool> {
+            Ok(false)
+        }
+        fn read_carrier_detect(&mut self) -> SerialResult<bool> {
+            Ok(false)
+        }
+        fn bytes_to_read(&self) -> SerialResult<u32> {
+            Ok(0)
+        }
+        fn bytes_to_write(&self) -> SerialResult<u32> {
+            Ok(0)
+        }
+        // The response is already waiting, keep it
+        fn clear(&self, _: ClearBuffer) -> SerialResult<()> {
+            Ok(())
+        }
+        fn try_clone(&self) -> SerialResult<Box<dyn SerialPort>> {
+            Err(serialport::Error::new(
+                serialport::ErrorKind::Unknown,
+                "not supported",
+            ))
+        }
+        fn set_break(&self) -> SerialResult<()> {
+            Ok(())
+        }
+        fn clear_break(&self) -> SerialResult<()> {
+            Ok(())
+        }
+    }
+
+    fn with_crc(frame: &[u8]) -> Vec<u8> {
+        let mut frame = frame.to_vec();
+        frame.extend_from_slice(&crc16(&frame).to_le_bytes());
+        frame
+    }
+
+    /// Transport for unit 0x11 which receives `response`
+    fn transport(response: Vec<u8>) -> (Transport, Arc<Mutex<Vec<u8>>>) {
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let port = FakePort {
+            written: written.clone(),
+            response: Cursor::new(response),
+        };
+        let transport = Transport {
+            port: Box::new(port),
+            uid: 0x11,
+            frame_delay: Duration::from_secs(0),
+            last_frame: Instant::now(),
+        };
+        (transport, written)
+    }
+
+    #[test]
+    fn test_read_holding_registers() {
+        // Modbus specification example
+        let response = with_crc(&[0x11, 0x03, 0x06, 0x02, 0x2B, 0x00, 0x00, 0x00, 0x64]);
+        let (mut mb, written) = transport(response);
+        assert_eq!(
+            mb.read_holding_registers(0x6B, 3).unwrap(),
+            vec![0x022B, 0x0000, 0x0064]
+        );
+        assert_eq!(
+            *written.lock().unwrap(),
+            vec![0x11, 0x03, 0x00, 0x6B, 0x00, 0x03, 0x76, 0x87]
+        );
+    }
+
+    #[test]
+    fn test_read_response_errors() {
+        let (mut mb, _) = transport(with_crc(&[0x11, 0x83, 0x02]));
+        assert!(matches!(
+            mb.read_holding_registers(0x6B, 1),
+            Err(Error::Exception(ExceptionCode::IllegalDataAddress))
+        ));
+
+        // Response of another unit
+        let (mut mb, _) = transport(with_crc(&[0x12, 0x03, 0x02, 0x00, 0x01]));
+        assert!(matches!(
+            mb.read_holding_registers(0x6B, 1),
+            Err(Error::InvalidResponse)
+        ));
+
+        // Corrupted data
+        let mut response = with_crc(&[0x11, 0x03, 0x02, 0x00, 0x01]);
+        response[4] = 0x02;
+        let (mut mb, _) = transport(response);
+        assert!(matches!(
+            mb.read_holding_registers(0x6B, 1),
+            Err(Error::InvalidData(Reason::DecodingError))
+        ));
+
+        // Incomplete frame
+        let (mut mb, _) = transport(vec![0x11, 0x03, 0x02]);
+        assert!(matches!(
+            mb.read_holding_registers(0x6B, 1),
+            Err(Error::Io(_))
+        ));
+    }
+
+    #[test]
+    fn test_crc16() {
+        // Read holding registers 0x006B..0x006E of unit 0x11 (Modbus specification example)
+        let frame = [0x11, 0x03, 0x00, 0x6B, 0x00, 0x03];
+        assert_eq!(crc16(&frame).to_le_bytes(), [0x76, 0x87]);
+    }
+
+    #[test]
+    fn test_default_frame_delay() {
+        let mut cfg = Config {
+            baud_rate: 9600,
+            parity: Parity::Even,
+            data_bits: DataBits::Eight,
+            stop_bits: StopBits::One,
+            timeout: Duration::from_secs(1),
+            frame_delay: None,
+        };
+        assert_eq!(cfg.default_frame_delay(), Duration::from_micros(4010));
+
+        cfg.baud_rate = 115_200;
+        assert_eq!(cfg.default_frame_delay(), Duration::from_micros(1750));
+    }
+}
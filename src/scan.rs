@@ -0,0 +1,148 @@
+use std::fmt::Write;
+use std::fs;
+
+use clap::{value_t, ArgMatches};
+use data_collector::config::{Setup, DEFAULT_CONNECTION};
+use data_collector::device::RegisterType;
+use log::debug;
+use modbus::{Client, Error as ModbusError, ExceptionCode};
+
+/// Highest unit id, larger ids are reserved
+const MAX_ID: u8 = 247;
+
+/// Answer of a unit id to the probe request
+#[derive(Debug, PartialEq)]
+enum Response {
+    Value(u16),
+    Exception(ExceptionCode),
+    Invalid(String),
+}
+
+/// Probes all unit ids on a connection and prints the ones which respond.
+pub fn run(setup: &Setup, matches: &ArgMatches) -> Result<(), String> {
+    let name = matches.value_of("connection").unwrap_or(DEFAULT_CONNECTION);
+    let config = setup
+        .connections
+        .get(name)
+        .ok_or_else(|| format!("Unknown connection `{}`", name))?;
+    let reg_type: RegisterType = matches.value_of("type").unwrap().parse().unwrap();
+    let addr = value_t!(matches, "addr", u16).unwrap_or_else(|e| e.exit());
+    let timeout = humantime::parse_duration(matches.value_of("timeout").unwrap())
+        .map_err(|e| format!("Invalid timeout: {}", e))?;
+
+    let connect = || {
+        config
+            .connect_with_timeout(timeout)
+            .map_err(|e| format!("{}: {}", name, e))
+    };
+    let mut mb = connect()?;
+
+    println!("{:<4} Response", "ID");
+    let mut found = Vec::new();
+    for id in 1..=MAX_ID {
+        mb.set_uid(id);
+        let response = match probe(mb.as_mut(), reg_type, addr) {
+            Ok(Some(response)) => response,
+            Ok(None) => continue,
+            Err(e) => {
+                debug!("{}: Unit id {}: {}", name, id, e);
+                // Drop late responses which would be mistaken for the next answer
+                mb = connect()?;
+                continue;
+            }
+        };
+
+        match &response {
+            Response::Value(v) => println!("{:<4} {} {} = {}", id, reg_type, addr, v),
+            Response::Exception(code) => println!("{:<4} Exception {:?}", id, code),
+            Response::Invalid(e) => println!("{:<4} Invalid response: {}", id, e),
+        }
+        found.push((id, response));
+    }
+    println!(
+        "{} of {} unit ids responded",
+        found.len(),
+        usize::from(MAX_ID)
+    );
+
+    if let Some(path) = matches.value_of("skeleton") {
+        let skeleton = skeleton(&found, name, reg_type, addr);
+        fs::write(path, skeleton).map_err(|e| format!("`{}`: {}", path, e))?;
+    }
+
+    Ok(())
+}
+
+/// Returns `None` if a gateway reports that the unit id did not respond.
+/// Timeouts and other transport errors are returned as error.
+fn probe(
+    mb: &mut dyn Client,
+    reg_type: RegisterType,
+    addr: u16,
+) -> Result<Option<Response>, ModbusError> {
+    match reg_type.read(mb, addr, 1) {
+        Ok(data) => Ok(Some(Response::Value(data[0]))),
+        Err(ModbusError::Exception(ExceptionCode::GatewayPath))
+        | Err(ModbusError::Exception(ExceptionCode::GatewayTarget)) => Ok(None),
+        Err(ModbusError::Exception(code)) => Ok(Some(Response::Exception(code))),
+        Err(ModbusError::Io(e)) => Err(ModbusError::Io(e)),
+        Err(e) => Ok(Some(Response::Invalid(e.to_string()))),
+    }
+}
+
+/// A `[[devices]]` entry for each responding unit id
+fn skeleton(
+    found: &[(u8, Response)],
+    connection: &str,
+    reg_type: RegisterType,
+    addr: u16,
+) -> String {
+    let mut config = String::new();
+    for (id, response) in found {
+        writeln!(config, "[[devices]]").unwrap();
+        writeln!(config, "id = {}", id).unwrap();
+        writeln!(config, "scan_interval = \"10s\"").unwrap();
+        if connection != DEFAULT_CONNECTION {
+            writeln!(config, "connection = \"{}\"", connection).unwrap();
+        }
+        match response {
+            Response::Value(_) => writeln!(config, "{}s = [{}]", reg_type, addr).unwrap(),
+            Response::Exception(code) => {
+                writeln!(config, "# {} {}: Exception {:?}", reg_type, addr, code).unwrap()
+            }
+            Response::Invalid(e) => {
+                writeln!(config, "# {} {}: Invalid response: {}", reg_type, addr, e).unwrap()
+            }
+        }
+        writeln!(config).unwrap();
+    }
+    config
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_skeleton() {
+        let found = vec![
+            (1, Response::Value(42)),
+            (7, Response::Exception(ExceptionCode::IllegalDataAddress)),
+        ];
+        assert_eq!(
+            skeleton(&found, "gw1", RegisterType::HoldingRegister, 100),
+            "[[devices]]\n\
+             id = 1\n\
+             scan_interval = \"10s\"\n\
+             connection = \"gw1\"\n\
+             holding_registers = [100]\n\
+             \n\
+             [[devices]]\n\
+             id = 7\n\
+             scan_interval = \"10s\"\n\
+             connection = \"gw1\"\n\
+             # holding_register 100: Exception IllegalDataAddress\n\
+             \n"
+        );
+    }
+}
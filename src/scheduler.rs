@@ -0,0 +1,381 @@
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryFrom;
+use std::io;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::device::Device;
+use crate::output::Output;
+use crate::source::Source;
+use futures::{channel::mpsc, executor, future, prelude::*, select, stream};
+use futures_timer::Interval;
+use log::{debug, error, info, warn};
+use modbus::{Client, Error as ModbusError};
+
+/// Polls the devices of each source in its own thread and passes the readings
+/// to the outputs, which send them in their own threads as well.
+pub struct Scheduler {
+    outputs: Vec<(String, Arc<dyn Output>)>,
+    source_threads: Vec<JoinHandle<()>>,
+    output_threads: Vec<JoinHandle<()>>,
+    shutdown: Shutdown,
+}
+
+impl Scheduler {
+    /// Starts polling. Each device is read from the source named in its `connection`,
+    /// sources without devices are not used.
+    ///
+    /// Fails when a device has no matching source or a zero scan interval,
+    /// or when the reconnect delay of a source is invalid.
+    pub fn start(
+        sources: Vec<(String, Box<dyn Source>)>,
+        devices: Vec<Device>,
+        outputs: Vec<(String, Arc<dyn Output>)>,
+    ) -> io::Result<Self> {
+        let source_names: BTreeSet<_> = sources.iter().map(|(name, _)| name.as_str()).collect();
+        for dev in &devices {
+            let invalid = |msg| {
+                let msg = format!("Device {} on `{}`: {}", dev.id, dev.connection, msg);
+                Err(io::Error::new(io::ErrorKind::InvalidInput, msg))
+            };
+            if !source_names.contains(dev.connection.as_str()) {
+                return invalid("No source for the connection");
+            }
+            if dev.scan_interval == Duration::from_secs(0) {
+                return invalid("`scan_interval` must not be zero");
+            }
+        }
+
+        let mut reconnect_max_delays = Vec::new();
+        for (name, source) in &sources {
+            let delay = source
+                .reconnect_max_delay()
+                .map_err(|e| io::Error::new(e.kind(), format!("Source `{}`: {}", name, e)))?;
+            reconnect_max_delays.push(delay);
+        }
+
+        // Every device belongs to exactly one connection
+        let mut devices_by_connection = BTreeMap::new();
+        for dev in devices {
+            devices_by_connection
+                .entry(dev.connection.clone())
+                .or_insert_with(Vec::new)
+                .push(dev);
+        }
+
+        // Poll each connection in its own thread so that a blocking connection
+        // does not delay the devices on other connections.
+        let mut shutdown_txs = Vec::new();
+        let mut source_threads = Vec::new();
+        for ((name, source), reconnect_max_delay) in sources.into_iter().zip(reconnect_max_delays) {
+            let devices = match devices_by_connection.remove(&name) {
+                Some(devices) => devices,
+                None => {
+                    warn!("{}: No devices configured, connection unused", name);
+                    continue;
+                }
+            };
+
+            let connection_outputs = outputs.clone();
+            let (shutdown_tx, shutdown_rx) = mpsc::channel(1);
+            shutdown_txs.push(shutdown_tx);
+
+            source_threads.push(thread::Builder::new().name(name.clone()).spawn(move || {
+                poll_connection(
+                    &name,
+                    source.as_ref(),
+                    reconnect_max_delay,
+                    &devices,
+                    &connection_outputs,
+                    shutdown_rx,
+                )
+            })?);
+        }
+
+        // Each output sends in a separate thread so that polling is not delayed
+        let mut output_threads = Vec::new();
+        for (name, output) in &outputs {
+            let output = output.clone();
+            output_threads.push(
+                thread::Builder::new()
+                    .name(name.clone())
+                    .spawn(move || output.run())?,
+            );
+        }
+
+        Ok(Self {
+            outputs,
+            source_threads,
+            output_threads,
+            shutdown: Shutdown(shutdown_txs),
+        })
+    }
+
+    /// Handle to stop the polling from another thread, e.g. a signal handler.
+    pub fn shutdown_handle(&self) -> Shutdown {
+        self.shutdown.clone()
+    }
+
+    /// Waits until the polling is stopped, then sends the remaining data of the outputs.
+    pub fn join(self) {
+        for thread in self.source_threads {
+            thread.join().expect("Connection thread panicked");
+        }
+
+        for (_, output) in &self.outputs {
+            output.close();
+        }
+        for thread in self.output_threads {
+            thread.join().expect("Output thread panicked");
+        }
+    }
+}
+
+/// Stops the polling of a `Scheduler`.
+#[derive(Clone)]
+pub struct Shutdown(Vec<mpsc::Sender<()>>);
+
+impl Shutdown {
+    pub fn shutdown(&self) {
+        for shutdown_tx in &self.0 {
+            // The thread might already have exited
+            let _ = shutdown_tx.clone().try_send(());
+        }
+    }
+}
+
+fn poll_connection(
+    name: &str,
+    source: &dyn Source,
+    reconnect_max_delay: Duration,
+    devices: &[Device],
+    outputs: &[(String, Arc<dyn Output>)],
+    mut shutdown_rx: mpsc::Receiver<()>,
+) {
+    // Connect Modbus
+    let connection = &RefCell::new(Connection::new(name, source, reconnect_max_delay));
+
+    // Share one failure counter for all devices of this connection.
+    // With each failed device communication the counter is increased.
+    // With each successfull device communicationt the counter is decreased.
+    // When the counter reaches the threshold (e.g. all devices on the bus failed
+    // two times in a row) action is taken.
+    let mut fail_count = 0;
+    let scan_interval_iter = devices.iter().map(|d| d.scan_interval.as_nanos());
+    let fail_count_threshold = 2
+        * devices.len()
+        * usize::try_from(
+            scan_interval_iter.clone().max().unwrap() / scan_interval_iter.clone().min().unwrap(),
+        )
+        .unwrap();
+    debug!("{}: fail_count_threshold={}", name, fail_count_threshold);
+
+    // A stream that yields a refence to a device every time its `scan_interval` is due.
+    let device_intervals = devices
+        .iter()
+        .map(|dev| Interval::new(dev.scan_interval).map(move |_| dev));
+
+    // Combine all device interval streams into one to process one device after the other.
+    let mut device_results = stream::select_all(device_intervals)
+        .filter_map(move |dev| {
+            // Devices are skipped while waiting for a reconnection
+            let mut connection = connection.borrow_mut();
+            let result = connection
+                .transport()
+                .map(|mb| process_device(dev, mb, outputs));
+            future::ready(result)
+        })
+        .inspect_ok(move |dev| {
+            debug!("{}: Device {} processed successfully", name, dev.id);
+            connection.borrow_mut().reset_reconnect_delay();
+        })
+        .inspect_err(|e| warn!("{}: Modbus: {}", name, e));
+
+    executor::block_on(async move {
+        loop {
+            select! {
+                _ = shutdown_rx.next() => {
+                    info!("{}: Graceful exit", name);
+                    break;
+                }
+                r = device_results.next() => {
+                    if r.unwrap().is_err() {
+                        fail_count += 1;
+                        debug!("{}: fail_count={}", name, fail_count);
+                    } else if fail_count > 0 {
+                        fail_count -= 1;
+                        debug!("{}: fail_count={}", name, fail_count);
+                    }
+
+                    if fail_count >= fail_count_threshold {
+                        error!("{}: {} modbus communication errors, reconnecting...", name, fail_count);
+                        connection.borrow_mut().disconnect();
+                        fail_count = 0;
+                    }
+                }
+            }
+        }
+    });
+}
+
+/// Modbus connection which is re-established with an exponential backoff delay.
+struct Connection<'a> {
+    name: &'a str,
+    source: &'a dyn Source,
+    transport: Option<Box<dyn Client>>,
+    reconnect_delay: Duration,
+    reconnect_max_delay: Duration,
+    next_connect: Instant,
+}
+
+impl<'a> Connection<'a> {
+    const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+    fn new(name: &'a str, source: &'a dyn Source, reconnect_max_delay: Duration) -> Self {
+        Self {
+            name,
+            source,
+            transport: None,
+            reconnect_delay: Self::MIN_RECONNECT_DELAY,
+            reconnect_max_delay,
+            next_connect: Instant::now(),
+        }
+    }
+
+    /// Returns the transport when connected.
+    /// (Re-)connects first if the reconnect delay has elapsed.
+    fn transport(&mut self) -> Option<&mut dyn Client> {
+        if self.transport.is_none() && Instant::now() >= self.next_connect {
+            match self.source.connect() {
+                Ok(mb) => {
+                    info!("{}: Connected", self.name);
+                    self.transport = Some(mb);
+                }
+                Err(e) => {
+                    error!("{}: {}", self.name, e);
+                    self.schedule_reconnect();
+                }
+            }
+        }
+
+        match &mut self.transport {
+            Some(mb) => Some(mb.as_mut()),
+            None => None,
+        }
+    }
+
+    fn disconnect(&mut self) {
+        self.transport = None;
+        self.schedule_reconnect();
+    }
+
+    fn schedule_reconnect(&mut self) {
+        let delay = cmp::min(self.reconnect_delay, self.reconnect_max_delay);
+        warn!(
+            "{}: Reconnecting in {}",
+            self.name,
+            humantime::format_duration(delay)
+        );
+        self.next_connect = Instant::now() + delay;
+        self.reconnect_delay = delay * 2;
+    }
+
+    fn reset_reconnect_delay(&mut self) {
+        self.reconnect_delay = Self::MIN_RECONNECT_DELAY;
+    }
+}
+
+fn process_device<'a>(
+    dev: &'a Device,
+    mb: &mut dyn Client,
+    outputs: &[(String, Arc<dyn Output>)],
+) -> Result<&'a Device, ModbusError> {
+    let reading = dev.read(mb)?;
+    for (name, output) in outputs {
+        let reading = reading.for_output(name);
+        if !reading.samples.is_empty() {
+            output.push(&reading);
+        }
+    }
+    Ok(dev)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::device::RequestOptions;
+
+    struct Unreachable;
+
+    impl Source for Unreachable {
+        fn connect(&self) -> Result<Box<dyn Client>, ModbusError> {
+            Err(ModbusError::Io(io::Error::from(
+                io::ErrorKind::ConnectionRefused,
+            )))
+        }
+    }
+
+    fn device(connection: &str, scan_interval: Duration) -> Device {
+        Device::new(
+            String::from(connection),
+            1,
+            scan_interval,
+            BTreeMap::new(),
+            BTreeMap::new(),
+            RequestOptions::default(),
+        )
+        .unwrap()
+    }
+
+    fn start(devices: Vec<Device>) -> io::Result<Scheduler> {
+        let sources: Vec<(String, Box<dyn Source>)> =
+            vec![(String::from("bus"), Box::new(Unreachable))];
+        Scheduler::start(sources, devices, Vec::new())
+    }
+
+    #[test]
+    fn test_start_errors() {
+        let e = start(vec![device("gw1", Duration::from_secs(1))])
+            .err()
+            .unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(
+            e.to_string(),
+            "Device 1 on `gw1`: No source for the connection"
+        );
+
+        let e = start(vec![device("bus", Duration::from_secs(0))])
+            .err()
+            .unwrap();
+        assert_eq!(
+            e.to_string(),
+            "Device 1 on `bus`: `scan_interval` must not be zero"
+        );
+
+        struct InvalidDelay;
+        impl Source for InvalidDelay {
+            fn connect(&self) -> Result<Box<dyn Client>, ModbusError> {
+                Unreachable.connect()
+            }
+
+            fn reconnect_max_delay(&self) -> io::Result<Duration> {
+                Err(io::Error::new(io::ErrorKind::InvalidInput, "Too long"))
+            }
+        }
+        let sources: Vec<(String, Box<dyn Source>)> =
+            vec![(String::from("bus"), Box::new(InvalidDelay))];
+        let devices = vec![device("bus", Duration::from_secs(1))];
+        let e = Scheduler::start(sources, devices, Vec::new())
+            .err()
+            .unwrap();
+        assert_eq!(e.to_string(), "Source `bus`: Too long");
+
+        let scheduler = start(vec![device("bus", Duration::from_secs(1))]).unwrap();
+        scheduler.shutdown_handle().shutdown();
+        scheduler.join();
+    }
+}
@@ -0,0 +1,582 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::f64::consts::PI;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use clap::ArgMatches;
+use data_collector::config::{Setup, DEFAULT_CONNECTION};
+use data_collector::device::{Device, Register, RegisterType, Value};
+use log::{info, warn};
+use modbus::ExceptionCode;
+use serde::Deserialize;
+
+/// File with the generators of the simulated registers
+#[derive(Deserialize)]
+struct ValuesConfig {
+    #[serde(default)]
+    registers: Vec<ValueConfig>,
+}
+
+#[derive(Deserialize)]
+struct ValueConfig {
+    id: u8,
+    name: String,
+    #[serde(flatten)]
+    generator: GeneratorConfig,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "generator", rename_all = "snake_case")]
+enum GeneratorConfig {
+    Constant {
+        value: f64,
+    },
+    Text {
+        text: String,
+    },
+    Ramp {
+        min: f64,
+        max: f64,
+        period: String,
+    },
+    Sine {
+        min: f64,
+        max: f64,
+        period: String,
+    },
+    RandomWalk {
+        min: f64,
+        max: f64,
+        step: f64,
+    },
+    Csv {
+        file: String,
+        column: Option<usize>,
+        interval: Option<String>,
+    },
+}
+
+/// Produces the values of a simulated register over time
+#[derive(Clone, Debug, PartialEq)]
+enum Generator {
+    Constant(Value),
+    /// Rises from `min` to `max` and jumps back every period
+    Ramp {
+        min: f64,
+        max: f64,
+        period: f64,
+    },
+    Sine {
+        min: f64,
+        max: f64,
+        period: f64,
+    },
+    /// Changes by up to `step` each time the register is read
+    RandomWalk {
+        min: f64,
+        max: f64,
+        step: f64,
+        value: f64,
+        seed: u64,
+    },
+    /// Cycles through the values, one per interval
+    Csv {
+        values: Vec<f64>,
+        interval: f64,
+    },
+}
+
+impl Generator {
+    fn from_config(config: GeneratorConfig) -> Result<Self, String> {
+        let parse_period = |period: &str| match humantime::parse_duration(period) {
+            Ok(d) if d > Duration::from_secs(0) => Ok(d.as_secs_f64()),
+            Ok(_) => Err(String::from("Period must not be zero")),
+            Err(e) => Err(format!("Invalid period: {}", e)),
+        };
+
+        Ok(match config {
+            GeneratorConfig::Constant { value } => Self::Constant(Value::Float(value)),
+            GeneratorConfig::Text { text } => Self::Constant(Value::String(text)),
+            GeneratorConfig::Ramp { min, max, period } => Self::Ramp {
+                min,
+                max,
+                period: parse_period(&period)?,
+            },
+            GeneratorConfig::Sine { min, max, period } => Self::Sine {
+                min,
+                max,
+                period: parse_period(&period)?,
+            },
+            GeneratorConfig::RandomWalk { min, max, step } => Self::RandomWalk {
+                min,
+                max,
+                step,
+                value: (min + max) / 2.0,
+                // Any odd number is a valid seed
+                seed: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_nanos() as u64
+                    | 1,
+            },
+            GeneratorConfig::Csv {
+                file,
+                column,
+                interval,
+            } => {
+                let content =
+                    fs::read_to_string(&file).map_err(|e| format!("`{}`: {}", file, e))?;
+                let values = parse_csv(&content, column.unwrap_or(0));
+                if values.is_empty() {
+                    return Err(format!("`{}`: No values", file));
+                }
+                Self::Csv {
+                    values,
+                    interval: parse_period(interval.as_deref().unwrap_or("1s"))?,
+                }
+            }
+        })
+    }
+
+    /// Value at `elapsed` seconds since the start of the simulation
+    fn value(&mut self, elapsed: f64) -> Value {
+        match self {
+            Self::Constant(value) => value.clone(),
+            Self::Ramp { min, max, period } => {
+                Value::Float(*min + (*max - *min) * (elapsed / *period).fract())
+            }
+            Self::Sine { min, max, period } => {
+                let phase = 2.0 * PI * elapsed / *period;
+                Value::Float(*min + (*max - *min) * (phase.sin() + 1.0) / 2.0)
+            }
+            Self::RandomWalk {
+                min,
+                max,
+                step,
+                value,
+                seed,
+            } => {
+                // xorshift, the walk does not need a good distribution
+                *seed ^= *seed << 13;
+                *seed ^= *seed >> 7;
+                *seed ^= *seed << 17;
+                let random = (*seed >> 11) as f64 / (1u64 << 53) as f64;
+                *value = (*value + *step * (2.0 * random - 1.0)).max(*min).min(*max);
+                Value::Float(*value)
+            }
+            Self::Csv { values, interval } => {
+                let idx = (elapsed / *interval) as usize % values.len();
+                Value::Float(values[idx])
+            }
+        }
+    }
+}
+
+/// Numbers in `column` of each line, lines without a number (e.g. the header) are skipped.
+fn parse_csv(content: &str, column: usize) -> Vec<f64> {
+    content
+        .lines()
+        .filter_map(|line| line.split(',').nth(column)?.trim().parse().ok())
+        .collect()
+}
+
+/// Simulated registers by address
+type Registers = BTreeMap<u16, (Register, Mutex<Generator>)>;
+
+/// Registers of the simulated devices by unit id and register type
+struct Simulator {
+    ids: BTreeSet<u8>,
+    registers: BTreeMap<(u8, RegisterType), Registers>,
+    start: Instant,
+}
+
+impl Simulator {
+    /// Registers without generator are constantly zero
+    fn new<'a>(
+        devices: impl Iterator<Item = &'a Device>,
+        values: Vec<ValueConfig>,
+    ) -> Result<Self, String> {
+        let mut ids = BTreeSet::new();
+        let mut registers: BTreeMap<_, Registers> = BTreeMap::new();
+        for device in devices {
+            ids.insert(device.id);
+            for (reg_type, addr, reg) in device.registers() {
+                let generator = Mutex::new(Generator::Constant(Value::Float(0.0)));
+                registers
+                    .entry((device.id, reg_type))
+                    .or_default()
+                    .insert(addr, (reg.clone(), generator));
+            }
+        }
+
+        for value in values {
+            let context = format!("Register `{}` of device `{}`", value.name, value.id);
+            let generator = Generator::from_config(value.generator)
+                .map_err(|e| format!("{}: {}", context, e))?;
+            let mut found = false;
+            for ((id, _), map) in registers.iter_mut() {
+                for (reg, reg_generator) in map.values_mut() {
+                    if *id == value.id && reg.name == value.name {
+                        *reg_generator.get_mut().unwrap() = generator.clone();
+                        found = true;
+                    }
+                }
+            }
+            if !found {
+                return Err(format!("{}: Unknown register", context));
+            }
+        }
+
+        Ok(Self {
+            ids,
+            registers,
+            start: Instant::now(),
+        })
+    }
+
+    /// Answers a request PDU, returns the response PDU
+    fn respond(&self, id: u8, pdu: &[u8]) -> Vec<u8> {
+        match self.handle(id, pdu) {
+            Ok(data) => [&[pdu[0]], &data[..]].concat(),
+            Err(code) => vec![pdu[0] | 0x80, code as u8],
+        }
+    }
+
+    fn handle(&self, id: u8, pdu: &[u8]) -> Result<Vec<u8>, ExceptionCode> {
+        if !self.ids.contains(&id) {
+            return Err(ExceptionCode::GatewayTarget);
+        }
+        let (reg_type, max_count) = match pdu[0] {
+            1 => (RegisterType::Coil, 2000),
+            2 => (RegisterType::DiscreteInput, 2000),
+            3 => (RegisterType::HoldingRegister, 125),
+            4 => (RegisterType::InputRegister, 125),
+            _ => return Err(ExceptionCode::IllegalFunction),
+        };
+        if pdu.len() != 5 {
+            return Err(ExceptionCode::IllegalDataValue);
+        }
+        let start = u16::from_be_bytes([pdu[1], pdu[2]]);
+        let count = u16::from_be_bytes([pdu[3], pdu[4]]);
+        if count == 0 || count > max_count {
+            return Err(ExceptionCode::IllegalDataValue);
+        }
+        if u32::from(start) + u32::from(count) > 0x10000 {
+            return Err(ExceptionCode::IllegalDataAddress);
+        }
+
+        let data = self.read(id, reg_type, start, count)?;
+        let mut resp = Vec::new();
+        match reg_type {
+            RegisterType::Coil | RegisterType::DiscreteInput => {
+                let bytes: Vec<u8> = data
+                    .chunks(8)
+                    .map(|chunk| {
+                        chunk
+                            .iter()
+                            .enumerate()
+                            .fold(0, |byte, (i, v)| byte | (u8::from(*v != 0) << i))
+                    })
+                    .collect();
+                resp.push(bytes.len() as u8);
+                resp.extend(bytes);
+            }
+            RegisterType::InputRegister | RegisterType::HoldingRegister => {
+                resp.push(2 * data.len() as u8);
+                resp.extend(data.iter().flat_map(|w| w.to_be_bytes().to_vec()));
+            }
+        }
+        Ok(resp)
+    }
+
+    /// Current contents of the addresses, unused addresses between registers are zero.
+    /// Fails when none of the addresses belongs to a register.
+    fn read(
+        &self,
+        id: u8,
+        reg_type: RegisterType,
+        start: u16,
+        count: u16,
+    ) -> Result<Vec<u16>, ExceptionCode> {
+        let registers = self
+            .registers
+            .get(&(id, reg_type))
+            .ok_or(ExceptionCode::IllegalDataAddress)?;
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let (start, end) = (u32::from(start), u32::from(start) + u32::from(count));
+
+        let mut data = vec![0; usize::from(count)];
+        let mut used = false;
+        for (addr, (reg, generator)) in registers {
+            let addr = u32::from(*addr);
+            let len = u32::from(reg.data_type.num_registers());
+            // Only advance the generators of the requested registers
+            if addr >= end || addr + len <= start {
+                continue;
+            }
+            used = true;
+            let value = generator.lock().unwrap().value(elapsed);
+            for (i, word) in reg.to_data(&value).into_iter().enumerate() {
+                let a = addr + i as u32;
+                if a >= start && a < end {
+                    data[(a - start) as usize] = word;
+                }
+            }
+        }
+
+        if used {
+            Ok(data)
+        } else {
+            Err(ExceptionCode::IllegalDataAddress)
+        }
+    }
+}
+
+/// Handles the requests of one client until it disconnects.
+fn serve(simulator: &Simulator, mut stream: TcpStream) -> io::Result<()> {
+    loop {
+        // MBAP header: transaction id, protocol id, length, unit id
+        let mut header = [0; 7];
+        match stream.read_exact(&mut header) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            result => result?,
+        }
+        let len = u16::from_be_bytes([header[4], header[5]]);
+        if !(2..=254).contains(&len) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid frame length {}", len),
+            ));
+        }
+        let mut pdu = vec![0; usize::from(len) - 1];
+        stream.read_exact(&mut pdu)?;
+
+        let resp = simulator.respond(header[6], &pdu);
+        let mut frame = header[..4].to_vec();
+        frame.extend(&(resp.len() as u16 + 1).to_be_bytes());
+        frame.push(header[6]);
+        frame.extend(resp);
+        stream.write_all(&frame)?;
+    }
+}
+
+/// Serves the devices of a connection as Modbus TCP slave with simulated values.
+pub fn run(setup: &Setup, matches: &ArgMatches) -> Result<(), String> {
+    let name = matches.value_of("connection").unwrap_or(DEFAULT_CONNECTION);
+    let config = setup
+        .connections
+        .get(name)
+        .ok_or_else(|| format!("Unknown connection `{}`", name))?;
+    let listen = match (matches.value_of("listen"), config.tcp_port()) {
+        (Some(listen), _) => String::from(listen),
+        (None, Some(port)) => format!("0.0.0.0:{}", port),
+        (None, None) => return Err(format!("{}: Serial connection, use `--listen`", name)),
+    };
+
+    let values = match matches.value_of("values") {
+        Some(path) => {
+            let content = fs::read_to_string(path).map_err(|e| format!("`{}`: {}", path, e))?;
+            toml::from_str::<ValuesConfig>(&content)
+                .map_err(|e| format!("`{}`: {}", path, e))?
+                .registers
+        }
+        None => Vec::new(),
+    };
+    let devices = setup.devices.iter().filter(|d| d.connection == name);
+    let simulator = Arc::new(Simulator::new(devices, values)?);
+    if simulator.ids.is_empty() {
+        return Err(format!("{}: No devices to simulate", name));
+    }
+
+    let listener =
+        TcpListener::bind(&listen).map_err(|e| format!("`{}`: Cannot listen: {}", listen, e))?;
+    println!(
+        "Simulating {} devices of connection `{}` on {}",
+        simulator.ids.len(),
+        name,
+        listen
+    );
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("{}", e);
+                continue;
+            }
+        };
+        let simulator = simulator.clone();
+        thread::spawn(move || {
+            let peer = stream
+                .peer_addr()
+                .map_or_else(|_| String::from("unknown"), |a| a.to_string());
+            info!("{}: Connected", peer);
+            match serve(&simulator, stream) {
+                Ok(()) => info!("{}: Disconnected", peer),
+                Err(e) => warn!("{}: {}", peer, e),
+            }
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use data_collector::config::DevicesConfig;
+
+    fn simulator(values: &str) -> Result<Simulator, String> {
+        let devices = toml::from_str::<DevicesConfig>(
+            r#"
+            [[devices]]
+            id = 1
+            scan_interval = "1s"
+            coils = [0, 1]
+
+            [[devices.input_registers]]
+            addr = 0
+            name = "power"
+            data_type = "f32"
+            byte_order = "CDAB"
+
+            [[devices.input_registers]]
+            addr = 4
+            name = "serial"
+            data_type = "string"
+            length = 2
+
+            [[devices.input_registers]]
+            addr = 8
+            name = "temperature"
+            data_type = "i16"
+            scaling = 0.1
+            "#,
+        )
+        .unwrap()
+        .into_devices()
+        .unwrap();
+        let values = toml::from_str::<ValuesConfig>(values).unwrap().registers;
+        Simulator::new(devices.iter(), values)
+    }
+
+    #[test]
+    fn test_generator_value() {
+        let mut ramp = Generator::Ramp {
+            min: 10.0,
+            max: 20.0,
+            period: 4.0,
+        };
+        assert_eq!(ramp.value(1.0), Value::Float(12.5));
+        assert_eq!(ramp.value(9.0), Value::Float(12.5));
+
+        let mut sine = Generator::Sine {
+            min: -1.0,
+            max: 1.0,
+            period: 4.0,
+        };
+        assert_eq!(sine.value(1.0), Value::Float(1.0));
+
+        let mut walk = Generator::RandomWalk {
+            min: 0.0,
+            max: 1.0,
+            step: 0.5,
+            value: 0.5,
+            seed: 1,
+        };
+        for _ in 0..100 {
+            match walk.value(0.0) {
+                Value::Float(v) => assert!((0.0..=1.0).contains(&v)),
+                v => panic!("Unexpected value {:?}", v),
+            }
+        }
+
+        let mut csv = Generator::Csv {
+            values: parse_csv("time,power\n0,1.5\n1,x\n2,-3\n", 1),
+            interval: 2.0,
+        };
+        assert_eq!(csv.value(1.0), Value::Float(1.5));
+        assert_eq!(csv.value(3.0), Value::Float(-3.0));
+        assert_eq!(csv.value(5.0), Value::Float(1.5));
+    }
+
+    #[test]
+    fn test_simulator_respond() {
+        let sim = simulator(
+            r#"
+            [[registers]]
+            id = 1
+            name = "power"
+            generator = "constant"
+            value = 1.5
+
+            [[registers]]
+            id = 1
+            name = "serial"
+            generator = "text"
+            text = "AB"
+
+            [[registers]]
+            id = 1
+            name = "temperature"
+            generator = "constant"
+            value = -2.5
+
+            [[registers]]
+            id = 1
+            name = "coil_1"
+            generator = "constant"
+            value = 1
+            "#,
+        )
+        .unwrap();
+
+        // F32 1.5 is 0x3FC00000, unused addresses between registers are zero
+        assert_eq!(
+            sim.respond(1, &[4, 0, 0, 0, 9]),
+            vec![
+                4, 18, 0x00, 0x00, 0x3F, 0xC0, 0, 0, 0, 0, b'A', b'B', 0, 0, 0, 0, 0, 0, 0xFF, 0xE7
+            ]
+        );
+        assert_eq!(sim.respond(1, &[1, 0, 0, 0, 2]), vec![1, 1, 0b10]);
+
+        assert_eq!(sim.respond(2, &[4, 0, 0, 0, 1]), vec![0x84, 0x0B]);
+        assert_eq!(sim.respond(1, &[3, 0, 0, 0, 1]), vec![0x83, 0x02]);
+        assert_eq!(sim.respond(1, &[4, 0, 20, 0, 1]), vec![0x84, 0x02]);
+        assert_eq!(sim.respond(1, &[4, 0, 0, 0, 126]), vec![0x84, 0x03]);
+        assert_eq!(sim.respond(1, &[6, 0, 0, 0, 1]), vec![0x86, 0x01]);
+    }
+
+    #[test]
+    fn test_simulator_errors() {
+        let unknown = r#"
+            [[registers]]
+            id = 1
+            name = "voltage"
+            generator = "constant"
+            value = 230
+            "#;
+        assert_eq!(
+            simulator(unknown).err().unwrap(),
+            "Register `voltage` of device `1`: Unknown register"
+        );
+
+        let zero_period = r#"
+            [[registers]]
+            id = 1
+            name = "power"
+            generator = "sine"
+            min = 0
+            max = 1
+            period = "0s"
+            "#;
+        assert_eq!(
+            simulator(zero_period).err().unwrap(),
+            "Register `power` of device `1`: Period must not be zero"
+        );
+    }
+}
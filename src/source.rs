@@ -0,0 +1,30 @@
+use std::io;
+use std::time::Duration;
+
+use crate::config::ModbusConfig;
+use modbus::{Client, Error};
+
+/// Modbus bus the devices are read from.
+///
+/// The scheduler polls each source in its own thread and connects again with an
+/// exponential backoff delay when the communication fails.
+pub trait Source: Send {
+    /// Opens a new connection, the previous one is dropped before.
+    fn connect(&self) -> Result<Box<dyn Client>, Error>;
+
+    /// Upper limit of the delay between connection attempts.
+    /// Checked once when the scheduler starts.
+    fn reconnect_max_delay(&self) -> io::Result<Duration> {
+        Ok(Duration::from_secs(60))
+    }
+}
+
+impl Source for ModbusConfig {
+    fn connect(&self) -> Result<Box<dyn Client>, Error> {
+        ModbusConfig::connect(self)
+    }
+
+    fn reconnect_max_delay(&self) -> io::Result<Duration> {
+        ModbusConfig::reconnect_max_delay(self)
+    }
+}
@@ -0,0 +1,238 @@
+mod common;
+
+use std::thread;
+use std::time::Duration;
+
+use common::{wait_until, Collector, InfluxDbServer, ModbusServer};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Two devices with a short scan interval, written to InfluxDB without delay
+fn config(modbus: &ModbusServer, influxdb: &InfluxDbServer, influxdb_extra: &str) -> String {
+    format!(
+        r#"
+        [modbus]
+        hostname = "127.0.0.1"
+        port = {}
+        timeout = "1s"
+
+        [outputs.local]
+        type = "influxdb"
+        hostname = "{}"
+        database = "test"
+        {}
+
+        [[devices]]
+        id = 1
+        scan_interval = "200ms"
+
+        [devices.tags]
+        site = "north"
+
+        [[devices.input_registers]]
+        addr = 0
+        name = "power"
+        data_type = "i32"
+        scaling = 0.1
+
+        [[devices]]
+        id = 2
+        scan_interval = "200ms"
+
+        [[devices.holding_registers]]
+        addr = 10
+        name = "status"
+        "#,
+        modbus.port(),
+        influxdb.url(),
+        influxdb_extra,
+    )
+}
+
+/// Only the `[modbus]` connection, enough for the commands which talk to devices directly
+fn connection_config(modbus: &ModbusServer) -> String {
+    format!(
+        r#"
+        [modbus]
+        hostname = "127.0.0.1"
+        port = {}
+        timeout = "1s"
+        "#,
+        modbus.port(),
+    )
+}
+
+fn modbus_server() -> ModbusServer {
+    let modbus = ModbusServer::start();
+    // -1234 as i32
+    modbus.set_register(1, 0, 0xFFFF);
+    modbus.set_register(1, 1, 0xFB2E);
+    modbus.set_register(2, 10, 7);
+    modbus
+}
+
+#[test]
+fn test_lines_written() {
+    let modbus = modbus_server();
+    let influxdb = InfluxDbServer::start();
+    let config = config(&modbus, &influxdb, r#"flush_interval = "100ms""#);
+    let _collector = Collector::start("lines", &config);
+
+    let power = "power,site=north,modbus_id=1 value=-123.4";
+    let status = "status,modbus_id=2 value=7";
+    assert!(wait_until(TIMEOUT, || influxdb.contains(power)
+        && influxdb.contains(status)));
+
+    // Values are updated with each scan
+    modbus.set_register(2, 10, 8);
+    assert!(wait_until(TIMEOUT, || influxdb.contains("status,modbus_id=2 value=8")));
+}
+
+#[test]
+fn test_gzip_lines_written() {
+    let modbus = modbus_server();
+    let influxdb = InfluxDbServer::start();
+    let config = config(
+        &modbus,
+        &influxdb,
+        "flush_interval = \"100ms\"\ngzip = true",
+    );
+    let _collector = Collector::start("gzip", &config);
+
+    let power = "power,site=north,modbus_id=1 value=-123.4";
+    let status = "status,modbus_id=2 value=7";
+    assert!(wait_until(TIMEOUT, || influxdb.contains(power)
+        && influxdb.contains(status)));
+    assert!(influxdb.gzip_requests() > 0);
+}
+
+#[test]
+fn test_exception_skips_device() {
+    let modbus = modbus_server();
+    let influxdb = InfluxDbServer::start();
+    // Device 2 answers with illegal data address
+    modbus.set_register(2, 10, 7);
+    let config =
+        config(&modbus, &influxdb, r#"flush_interval = "100ms""#).replace("addr = 10", "addr = 11");
+    let collector = Collector::start("exception", &config);
+
+    assert!(wait_until(TIMEOUT, || influxdb
+        .contains("power,site=north,modbus_id=1 value=-123.4")));
+    assert!(influxdb.lines().iter().all(|l| !l.starts_with("status")));
+
+    // The successful reads of device 1 keep the failure counter below the threshold
+    thread::sleep(Duration::from_secs(1));
+    assert_eq!(modbus.connections(), 1);
+    assert!(collector.log().contains("IllegalDataAddress"));
+}
+
+#[test]
+fn test_reconnect_after_failures() {
+    let modbus = modbus_server();
+    let influxdb = InfluxDbServer::start();
+    let config = config(&modbus, &influxdb, r#"flush_interval = "100ms""#);
+    let collector = Collector::start("failures", &config);
+    assert!(wait_until(TIMEOUT, || influxdb.contains("status,modbus_id=2 value=7")));
+
+    // All devices fail, the connection is closed and opened again
+    modbus.set_failing(true);
+    assert!(wait_until(TIMEOUT, || modbus.connections() >= 2));
+    assert!(collector
+        .log()
+        .contains("modbus communication errors, reconnecting"));
+
+    modbus.set_register(2, 10, 9);
+    modbus.set_failing(false);
+    assert!(wait_until(TIMEOUT, || influxdb.contains("status,modbus_id=2 value=9")));
+}
+
+#[test]
+fn test_reconnect_after_connection_loss() {
+    let modbus = modbus_server();
+    let influxdb = InfluxDbServer::start();
+    let config = config(&modbus, &influxdb, r#"flush_interval = "100ms""#);
+    let _collector = Collector::start("connection_loss", &config);
+    assert!(wait_until(TIMEOUT, || influxdb.contains("status,modbus_id=2 value=7")));
+
+    modbus.disconnect_all();
+    modbus.set_register(2, 10, 9);
+    assert!(wait_until(TIMEOUT, || modbus.connections() >= 2));
+    assert!(wait_until(TIMEOUT, || influxdb.contains("status,modbus_id=2 value=9")));
+}
+
+#[test]
+fn test_buffer_replayed_after_influxdb_error() {
+    let modbus = modbus_server();
+    let influxdb = InfluxDbServer::start();
+    influxdb.fail_next(1);
+    let config = config(
+        &modbus,
+        &influxdb,
+        r#"
+        flush_interval = "100ms"
+
+        [outputs.local.buffer]
+        path = "{dir}/buffer"
+        "#,
+    );
+    let _collector = Collector::start("buffer", &config);
+
+    // The rejected batch is kept and sent again after the retry delay
+    assert!(wait_until(TIMEOUT, || influxdb.requests() >= 2));
+    let rejected = influxdb.rejected_lines();
+    assert!(!rejected.is_empty());
+    assert!(wait_until(TIMEOUT, || {
+        let received = influxdb.raw_lines();
+        rejected.iter().all(|line| received.contains(line))
+    }));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_graceful_shutdown_flushes_queue() {
+    let modbus = modbus_server();
+    let influxdb = InfluxDbServer::start();
+    // Nothing is sent before the shutdown
+    let config = config(&modbus, &influxdb, r#"flush_interval = "1h""#);
+    let mut collector = Collector::start("shutdown", &config);
+    assert!(wait_until(TIMEOUT, || collector
+        .log()
+        .contains("Device 2 processed successfully")));
+    assert_eq!(influxdb.requests(), 0);
+
+    let status = collector
+        .interrupt(TIMEOUT)
+        .expect("Collector did not exit");
+    assert!(status.success());
+    assert!(collector.log().contains("Graceful exit"));
+    assert!(influxdb.contains("power,site=north,modbus_id=1 value=-123.4"));
+    assert!(influxdb.contains("status,modbus_id=2 value=7"));
+}
+
+#[test]
+fn test_scan_without_devices_and_outputs() {
+    let modbus = modbus_server();
+    let output = Collector::run(
+        "scan",
+        &connection_config(&modbus),
+        &["scan", "--addr", "10"],
+    );
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", stdout);
+    assert!(stdout.contains("2    holding_register 10 = 7"));
+    assert!(stdout.contains("247 of 247 unit ids responded"));
+}
+
+#[test]
+fn test_probe_without_devices_and_outputs() {
+    let modbus = modbus_server();
+    let output = Collector::run(
+        "probe",
+        &connection_config(&modbus),
+        &["probe", "1", "--range", "0..3"],
+    );
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", stdout);
+    assert!(stdout.contains("input_register\n  0..=1          Data\n"));
+    assert!(stdout.contains("holding_register\n  0..=1          Data\n"));
+}
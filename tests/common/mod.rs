@@ -0,0 +1,345 @@
+//! Test harness to run the collector binary against an in-process Modbus TCP server
+//! and a fake InfluxDB HTTP endpoint.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::{self, Child, Command, ExitStatus, Output, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use flate2::read::GzDecoder;
+
+/// Polls `condition` until it is true or `timeout` elapsed.
+pub fn wait_until(timeout: Duration, mut condition: impl FnMut() -> bool) -> bool {
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        if condition() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    condition()
+}
+
+#[derive(Default)]
+struct ModbusState {
+    /// Input and holding registers share the values
+    registers: Mutex<HashMap<(u8, u16), u16>>,
+    /// Answer all requests with an exception
+    failing: AtomicBool,
+    connections: AtomicUsize,
+    streams: Mutex<Vec<TcpStream>>,
+}
+
+/// Modbus TCP server which answers reads of input and holding registers.
+/// Addresses without value are answered with an illegal data address exception.
+pub struct ModbusServer {
+    port: u16,
+    state: Arc<ModbusState>,
+}
+
+impl ModbusServer {
+    pub fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let state = Arc::new(ModbusState::default());
+
+        let accept_state = state.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+                accept_state.connections.fetch_add(1, Ordering::SeqCst);
+                accept_state
+                    .streams
+                    .lock()
+                    .unwrap()
+                    .push(stream.try_clone().unwrap());
+                let state = accept_state.clone();
+                thread::spawn(move || {
+                    let _ = serve_modbus(&state, stream);
+                });
+            }
+        });
+
+        Self { port, state }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn set_register(&self, id: u8, addr: u16, value: u16) {
+        self.state
+            .registers
+            .lock()
+            .unwrap()
+            .insert((id, addr), value);
+    }
+
+    pub fn set_failing(&self, failing: bool) {
+        self.state.failing.store(failing, Ordering::SeqCst);
+    }
+
+    /// Number of accepted connections
+    pub fn connections(&self) -> usize {
+        self.state.connections.load(Ordering::SeqCst)
+    }
+
+    /// Closes all open connections
+    pub fn disconnect_all(&self) {
+        for stream in self.state.streams.lock().unwrap().drain(..) {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+fn serve_modbus(state: &ModbusState, mut stream: TcpStream) -> io::Result<()> {
+    loop {
+        let mut header = [0; 7];
+        stream.read_exact(&mut header)?;
+        let len = u16::from_be_bytes([header[4], header[5]]);
+        let mut pdu = vec![0; usize::from(len) - 1];
+        stream.read_exact(&mut pdu)?;
+
+        let function = pdu[0];
+        let start = u16::from_be_bytes([pdu[1], pdu[2]]);
+        let count = u16::from_be_bytes([pdu[3], pdu[4]]);
+        let values: Option<Vec<u16>> = {
+            let registers = state.registers.lock().unwrap();
+            (start..start + count)
+                .map(|addr| registers.get(&(header[6], addr)).copied())
+                .collect()
+        };
+
+        let resp = match values {
+            // Slave device failure
+            _ if state.failing.load(Ordering::SeqCst) => vec![function | 0x80, 0x04],
+            // Illegal function
+            _ if function != 3 && function != 4 => vec![function | 0x80, 0x01],
+            // Illegal data address
+            None => vec![function | 0x80, 0x02],
+            Some(values) => {
+                let mut resp = vec![function, 2 * values.len() as u8];
+                resp.extend(values.iter().flat_map(|v| v.to_be_bytes().to_vec()));
+                resp
+            }
+        };
+
+        let mut frame = header[..4].to_vec();
+        frame.extend(&(resp.len() as u16 + 1).to_be_bytes());
+        frame.push(header[6]);
+        frame.extend(resp);
+        stream.write_all(&frame)?;
+    }
+}
+
+#[derive(Default)]
+struct InfluxDbState {
+    lines: Mutex<Vec<String>>,
+    rejected: Mutex<Vec<String>>,
+    /// Number of requests still to be answered with an error
+    failures: AtomicUsize,
+    requests: AtomicUsize,
+    gzip_requests: AtomicUsize,
+}
+
+/// HTTP server which stores the line protocol of InfluxDB write requests.
+pub struct InfluxDbServer {
+    port: u16,
+    state: Arc<InfluxDbState>,
+}
+
+impl InfluxDbServer {
+    pub fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let state = Arc::new(InfluxDbState::default());
+
+        let accept_state = state.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let state = accept_state.clone();
+                let stream = stream.unwrap();
+                thread::spawn(move || {
+                    let _ = serve_http(&state, stream);
+                });
+            }
+        });
+
+        Self { port, state }
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://127.0.0.1:{}", self.port)
+    }
+
+    /// Answers the next `n` requests with an internal server error.
+    pub fn fail_next(&self, n: usize) {
+        self.state.failures.store(n, Ordering::SeqCst);
+    }
+
+    pub fn requests(&self) -> usize {
+        self.state.requests.load(Ordering::SeqCst)
+    }
+
+    /// Number of requests with a gzip compressed body
+    pub fn gzip_requests(&self) -> usize {
+        self.state.gzip_requests.load(Ordering::SeqCst)
+    }
+
+    /// Received lines including their timestamp
+    pub fn raw_lines(&self) -> Vec<String> {
+        self.state.lines.lock().unwrap().clone()
+    }
+
+    /// Lines of the requests answered with an error
+    pub fn rejected_lines(&self) -> Vec<String> {
+        self.state.rejected.lock().unwrap().clone()
+    }
+
+    /// Received lines without their timestamp
+    pub fn lines(&self) -> Vec<String> {
+        self.raw_lines()
+            .iter()
+            .map(|line| line.rsplit_once(' ').unwrap().0.to_string())
+            .collect()
+    }
+
+    pub fn contains(&self, line: &str) -> bool {
+        self.lines().iter().any(|l| l == line)
+    }
+}
+
+fn serve_http(state: &InfluxDbState, stream: TcpStream) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut stream = stream;
+    loop {
+        let mut content_length = 0;
+        let mut gzip = false;
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        loop {
+            line.clear();
+            reader.read_line(&mut line)?;
+            let header = line.trim_end().to_ascii_lowercase();
+            if header.is_empty() {
+                break;
+            }
+            if let Some(len) = header.strip_prefix("content-length:") {
+                content_length = len.trim().parse().unwrap();
+            }
+            if header == "content-encoding: gzip" {
+                gzip = true;
+            }
+        }
+
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body)?;
+        let mut text = String::new();
+        if gzip {
+            state.gzip_requests.fetch_add(1, Ordering::SeqCst);
+            GzDecoder::new(&body[..]).read_to_string(&mut text)?;
+        } else {
+            text = String::from_utf8(body).unwrap();
+        }
+
+        state.requests.fetch_add(1, Ordering::SeqCst);
+        let failures = state.failures.load(Ordering::SeqCst);
+        let (status, lines) = if failures > 0 {
+            state.failures.store(failures - 1, Ordering::SeqCst);
+            ("500 Internal Server Error", &state.rejected)
+        } else {
+            ("204 No Content", &state.lines)
+        };
+        lines.lock().unwrap().extend(text.lines().map(String::from));
+        write!(stream, "HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status)?;
+    }
+}
+
+/// Collector process with its own working directory, killed when dropped.
+pub struct Collector {
+    child: Child,
+    dir: PathBuf,
+}
+
+impl Collector {
+    /// Starts the collector in a new directory. `{dir}` in the configuration is
+    /// replaced with the path of the directory.
+    pub fn start(name: &str, config: &str) -> Self {
+        let dir =
+            std::env::temp_dir().join(format!("data-collector-test-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let config_path = dir.join("config.toml");
+        fs::write(&config_path, config.replace("{dir}", dir.to_str().unwrap())).unwrap();
+
+        let child = Command::new(env!("CARGO_BIN_EXE_data-collector"))
+            .arg("--config")
+            .arg(&config_path)
+            .arg("--loglevel")
+            .arg("debug")
+            .arg("--logfile")
+            .arg(dir.join("collector.log"))
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap();
+
+        Self { child, dir }
+    }
+
+    /// Runs a command of the collector to completion in a new directory.
+    pub fn run(name: &str, config: &str, args: &[&str]) -> Output {
+        let dir =
+            std::env::temp_dir().join(format!("data-collector-test-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let config_path = dir.join("config.toml");
+        fs::write(&config_path, config).unwrap();
+
+        let output = Command::new(env!("CARGO_BIN_EXE_data-collector"))
+            .arg("--config")
+            .arg(&config_path)
+            .args(args)
+            .output()
+            .unwrap();
+        let _ = fs::remove_dir_all(&dir);
+        output
+    }
+
+    pub fn log(&self) -> String {
+        fs::read_to_string(self.dir.join("collector.log")).unwrap_or_default()
+    }
+
+    /// Sends SIGINT and waits for the process to exit.
+    #[cfg(unix)]
+    pub fn interrupt(&mut self, timeout: Duration) -> Option<ExitStatus> {
+        let status = Command::new("kill")
+            .arg("-INT")
+            .arg(self.child.id().to_string())
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let mut exit_status = None;
+        wait_until(timeout, || {
+            exit_status = self.child.try_wait().unwrap();
+            exit_status.is_some()
+        });
+        exit_status
+    }
+}
+
+impl Drop for Collector {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}